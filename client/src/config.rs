@@ -1,4 +1,8 @@
-use serde::{Deserialize, Serialize};
+use wsld_common::one_or_many;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::time::Duration;
 
 fn default_service_port() -> u32 {
@@ -7,34 +11,100 @@ fn default_service_port() -> u32 {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
+    // Path the config was read from
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+
     #[serde(default = "default_service_port")]
     pub service_port: u32,
 
+    // File containing the key shared with wsldhost, to authenticate requests
     #[serde(default)]
-    pub time: Option<TimeConfig>,
+    pub auth_key_file: Option<PathBuf>,
 
     #[serde(default)]
-    pub x11: Option<X11Config>,
+    pub limits: LimitConfig,
+
+    #[serde(default)]
+    pub time: Option<TimeConfig>,
+
+    // Either a single [x11] table, or an array of [[x11]] tables
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub x11: Vec<X11Config>,
 
     #[serde(default)]
     pub tcp_forward: Option<TcpForwardConfig>,
 
+    #[serde(default)]
+    pub udp_forward: Option<UdpForwardConfig>,
+
     #[serde(default)]
     pub ssh_agent: Option<SshAgentConfig>,
+
+    #[serde(default)]
+    pub socks: Option<SocksConfig>,
+
+    #[serde(default)]
+    pub http_proxy: Option<HttpProxyConfig>,
+
+    #[serde(default)]
+    pub dns: Option<DnsConfig>,
+
+    #[serde(default)]
+    pub reverse_forward: Vec<ReverseForwardConfig>,
+
+    #[serde(default)]
+    pub unix_forward: Vec<UnixForwardConfig>,
+
+    #[serde(default)]
+    pub pipe_forward: Vec<PipeForwardConfig>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
+            path: None,
             service_port: default_service_port(),
+            auth_key_file: None,
+            limits: Default::default(),
             time: None,
-            x11: None,
+            x11: Vec::new(),
             tcp_forward: None,
+            udp_forward: None,
             ssh_agent: None,
+            socks: None,
+            http_proxy: None,
+            dns: None,
+            reverse_forward: Vec::new(),
+            unix_forward: Vec::new(),
+            pipe_forward: Vec::new(),
         }
     }
 }
 
+/// Limits on accepted connections, either of a service or of all services together.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LimitConfig {
+    // Maximum number of connections open at the same time
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+
+    // Maximum number of connections accepted per second
+    #[serde(default)]
+    pub max_accept_rate: Option<u32>,
+}
+
+/// Users and groups allowed to connect to a Unix socket. If both are empty, only the user
+/// running wsld (and root) is allowed.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PeerConfig {
+    #[serde(default)]
+    pub allowed_uids: Vec<u32>,
+
+    #[serde(default)]
+    pub allowed_gids: Vec<u32>,
+}
+
 fn default_interval() -> Duration {
     // Every 10 minutes
     Duration::from_secs(600)
@@ -47,18 +117,48 @@ pub struct TimeConfig {
     pub interval: Duration,
 }
 
-fn default_display() -> u32 {
+fn default_true() -> bool {
+    true
+}
+
+fn default_display() -> X11Display {
     // Display :0
-    0
+    X11Display::Number(0)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct X11Config {
     #[serde(default = "default_display")]
-    pub display: u32,
+    pub display: X11Display,
 
     #[serde(default)]
     pub force: bool,
+
+    // Listen on /tmp/.X11-unix/X<n>
+    #[serde(default = "default_true")]
+    pub listen_unix: bool,
+
+    // Listen on @/tmp/.X11-unix/X<n> in the abstract namespace
+    #[serde(default)]
+    pub listen_abstract: bool,
+
+    // Listen on 127.0.0.1:6000+n
+    #[serde(default)]
+    pub listen_tcp: bool,
+
+    // Index of the X server to connect to, among the displays specified to wsldhost
+    #[serde(default)]
+    pub host_display: u8,
+
+    // Require clients to present a cookie generated by wsld, which is added with xauth
+    #[serde(default)]
+    pub auth: bool,
+
+    #[serde(flatten)]
+    pub limits: LimitConfig,
+
+    #[serde(flatten)]
+    pub peers: PeerConfig,
 }
 
 impl Default for X11Config {
@@ -66,6 +166,48 @@ impl Default for X11Config {
         X11Config {
             display: default_display(),
             force: false,
+            listen_unix: true,
+            listen_abstract: false,
+            listen_tcp: false,
+            host_display: 0,
+            auth: false,
+            limits: Default::default(),
+            peers: Default::default(),
+        }
+    }
+}
+
+/// An X11 display number, or `"auto"` for the first free one.
+#[derive(Debug, Clone, Copy)]
+pub enum X11Display {
+    Auto,
+    Number(u32),
+}
+
+impl Serialize for X11Display {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            X11Display::Auto => serializer.serialize_str("auto"),
+            X11Display::Number(display) => serializer.serialize_u32(*display),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for X11Display {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Number(u32),
+            Name(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Number(display) => Ok(X11Display::Number(display)),
+            Repr::Name(name) if name == "auto" => Ok(X11Display::Auto),
+            Repr::Name(name) => Err(D::Error::custom(format!("invalid display {:?}", name))),
         }
     }
 }
@@ -79,6 +221,10 @@ fn default_iptables_cmd() -> String {
     "sudo iptables-legacy".to_owned()
 }
 
+fn default_listener_check_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TcpForwardConfig {
     #[serde(default = "default_tcp_service_port")]
@@ -87,7 +233,215 @@ pub struct TcpForwardConfig {
     #[serde(default = "default_iptables_cmd")]
     pub iptables_cmd: String,
 
+    #[serde(flatten)]
+    pub scope: RedirectScope,
+
+    #[serde(default)]
+    pub host_ip: bool,
+
+    #[serde(default)]
+    pub virtual_ips: Vec<Ipv4Addr>,
+
+    #[serde(default)]
     pub ports: Vec<u16>,
+
+    #[serde(default)]
+    pub mirror: Option<MirrorConfig>,
+
+    #[serde(default = "default_listener_check_interval")]
+    #[serde(with = "humantime_serde")]
+    pub listener_check_interval: Duration,
+
+    // Close connections without any traffic for this long
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Option<Duration>,
+
+    #[serde(default)]
+    pub keepalive: Option<KeepaliveConfig>,
+
+    #[serde(default)]
+    pub port_options: Vec<PortOptions>,
+
+    #[serde(flatten)]
+    pub limits: LimitConfig,
+}
+
+impl TcpForwardConfig {
+    /// Get the options of `port`. The first matching entry wins.
+    pub fn port_options(&self, port: u16) -> Option<&PortOptions> {
+        self.port_options
+            .iter()
+            .find(|options| options.ports.iter().any(|range| range.contains(port)))
+    }
+}
+
+/// Settings that only apply to some of the forwarded ports.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PortOptions {
+    pub ports: Vec<PortRange>,
+
+    // Send a PROXY protocol header carrying the original addresses to the Windows service
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocol>,
+
+    // Overrides `idle_timeout` of tcp_forward
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Option<Duration>,
+
+    // Close connections after being open for this long
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub max_lifetime: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
+/// Restricts redirection to traffic from matching processes. All processes are matched if
+/// nothing is specified.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RedirectScope {
+    // User name or UID
+    #[serde(default)]
+    pub uid: Option<String>,
+
+    // Group name or GID
+    #[serde(default)]
+    pub gid: Option<String>,
+
+    // cgroup v2 path
+    #[serde(default)]
+    pub cgroup: Option<String>,
+}
+
+fn default_keepalive_time() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_keepalive_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_keepalive_retries() -> u32 {
+    3
+}
+
+/// TCP keepalive parameters of forwarded connections.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeepaliveConfig {
+    // Idle time before the first probe
+    #[serde(default = "default_keepalive_time")]
+    #[serde(with = "humantime_serde")]
+    pub time: Duration,
+
+    #[serde(default = "default_keepalive_interval")]
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+
+    #[serde(default = "default_keepalive_retries")]
+    pub retries: u32,
+}
+
+fn default_mirror_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MirrorConfig {
+    #[serde(default = "default_mirror_interval")]
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+
+    #[serde(default)]
+    pub include: Vec<PortRange>,
+
+    #[serde(default)]
+    pub exclude: Vec<PortRange>,
+}
+
+impl MirrorConfig {
+    pub fn matches(&self, port: u16) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|range| range.contains(port)))
+            && !self.exclude.iter().any(|range| range.contains(port))
+    }
+}
+
+/// An inclusive range of ports, written as either `1234` or `"1000-2000"` in the config.
+#[derive(Debug, Clone, Copy)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.start == self.end {
+            serializer.serialize_u16(self.start)
+        } else {
+            serializer.serialize_str(&format!("{}-{}", self.start, self.end))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Port(u16),
+            Range(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Port(port) => Ok(PortRange {
+                start: port,
+                end: port,
+            }),
+            Repr::Range(str) => {
+                let invalid = || D::Error::custom(format!("invalid port range {:?}", str));
+                let (start, end) = str.split_once('-').ok_or_else(invalid)?;
+                let start = start.trim().parse().map_err(|_| invalid())?;
+                let end = end.trim().parse().map_err(|_| invalid())?;
+                if start > end {
+                    return Err(invalid());
+                }
+                Ok(PortRange { start, end })
+            }
+        }
+    }
+}
+
+fn default_udp_idle_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UdpForwardConfig {
+    #[serde(default = "default_iptables_cmd")]
+    pub iptables_cmd: String,
+
+    #[serde(flatten)]
+    pub scope: RedirectScope,
+
+    pub ports: Vec<u16>,
+
+    #[serde(default = "default_udp_idle_timeout")]
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Duration,
 }
 
 fn default_ssh_auth_sock() -> String {
@@ -98,4 +452,185 @@ fn default_ssh_auth_sock() -> String {
 pub struct SshAgentConfig {
     #[serde(default = "default_ssh_auth_sock")]
     pub ssh_auth_sock: String,
+
+    #[serde(flatten)]
+    pub limits: LimitConfig,
+
+    #[serde(flatten)]
+    pub peers: PeerConfig,
+}
+
+fn default_socks_listen() -> SocketAddr {
+    ([127, 0, 0, 1], 1080).into()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SocksConfig {
+    #[serde(default = "default_socks_listen")]
+    pub listen: SocketAddr,
+
+    #[serde(flatten)]
+    pub limits: LimitConfig,
+}
+
+fn default_http_proxy_listen() -> SocketAddr {
+    ([127, 0, 0, 1], 3128).into()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HttpProxyConfig {
+    #[serde(default = "default_http_proxy_listen")]
+    pub listen: SocketAddr,
+
+    #[serde(flatten)]
+    pub limits: LimitConfig,
+}
+
+fn default_dns_listen() -> SocketAddr {
+    ([127, 0, 0, 53], 53).into()
+}
+
+fn default_dns_cache_size() -> usize {
+    1024
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DnsConfig {
+    #[serde(default = "default_dns_listen")]
+    pub listen: SocketAddr,
+
+    #[serde(default = "default_dns_cache_size")]
+    pub cache_size: usize,
+
+    #[serde(default)]
+    pub rewrite_resolv_conf: bool,
+
+    #[serde(flatten)]
+    pub limits: LimitConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReverseForwardConfig {
+    // Port to listen on Windows localhost
+    pub port: u16,
+
+    pub target: ReverseTarget,
+
+    #[serde(flatten)]
+    pub limits: LimitConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ReverseTarget {
+    // TCP port on WSL localhost
+    Port(u16),
+    // Unix socket path
+    Path(PathBuf),
+}
+
+fn default_socket_mode() -> u32 {
+    0o600
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnixForwardConfig {
+    pub path: String,
+
+    #[serde(default = "default_socket_mode")]
+    pub mode: u32,
+
+    // Port on Windows localhost to forward to
+    pub target: u16,
+
+    #[serde(flatten)]
+    pub limits: LimitConfig,
+
+    #[serde(flatten)]
+    pub peers: PeerConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PipeForwardConfig {
+    pub path: String,
+
+    #[serde(default = "default_socket_mode")]
+    pub mode: u32,
+
+    // Name of the pipe, without the \\.\pipe\ prefix
+    pub pipe: String,
+
+    #[serde(flatten)]
+    pub limits: LimitConfig,
+
+    #[serde(flatten)]
+    pub peers: PeerConfig,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Ranges {
+        ranges: Vec<PortRange>,
+    }
+
+    fn parse_ranges(value: &str) -> Result<Vec<PortRange>, toml::de::Error> {
+        toml::from_str::<Ranges>(&format!("ranges = {}", value)).map(|ranges| ranges.ranges)
+    }
+
+    fn mirror(include: &str, exclude: &str) -> MirrorConfig {
+        toml::from_str(&format!("include = {}\nexclude = {}", include, exclude)).unwrap()
+    }
+
+    #[test]
+    fn port_range_parsing() {
+        let ranges = parse_ranges(r#"[ 80, "8000-8100", " 3000 - 3001 ", "5-5" ]"#).unwrap();
+        let bounds: Vec<_> = ranges
+            .iter()
+            .map(|range| (range.start, range.end))
+            .collect();
+        assert_eq!(bounds, [(80, 80), (8000, 8100), (3000, 3001), (5, 5)]);
+
+        assert!(parse_ranges(r#"[ "8100-8000" ]"#).is_err());
+        assert!(parse_ranges(r#"[ "8000" ]"#).is_err());
+        assert!(parse_ranges(r#"[ "a-b" ]"#).is_err());
+        assert!(parse_ranges(r#"[ "1-70000" ]"#).is_err());
+        assert!(parse_ranges("[ 70000 ]").is_err());
+    }
+
+    #[test]
+    fn port_range_contains() {
+        let range = PortRange {
+            start: 8000,
+            end: 8100,
+        };
+        assert!(range.contains(8000));
+        assert!(range.contains(8100));
+        assert!(!range.contains(7999));
+        assert!(!range.contains(8101));
+    }
+
+    #[test]
+    fn mirror_matches() {
+        let all = mirror("[]", "[]");
+        assert!(all.matches(1));
+        assert!(all.matches(65535));
+
+        let included = mirror(r#"[ 3000, "8000-8100" ]"#, "[]");
+        assert!(included.matches(3000));
+        assert!(included.matches(8050));
+        assert!(!included.matches(3001));
+
+        let excluded = mirror("[]", r#"[ "1-1023" ]"#);
+        assert!(!excluded.matches(22));
+        assert!(excluded.matches(1024));
+
+        // Exclusions take precedence over inclusions.
+        let both = mirror(r#"[ "8000-8100" ]"#, "[ 8080 ]");
+        assert!(both.matches(8000));
+        assert!(!both.matches(8080));
+        assert!(!both.matches(9000));
+    }
 }
@@ -0,0 +1,45 @@
+use super::tcp::connect_target;
+use super::util::connect_stream;
+
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+// How long to keep sending stdin after the server has closed the connection.
+const STDIN_GRACE: Duration = Duration::from_millis(500);
+
+/// `wsld connect [HOST] PORT`: connect to a port on Windows and bridge it to stdin/stdout.
+pub async fn connect(args: &[String]) -> std::io::Result<()> {
+    let (host, port) = match args {
+        [port] => (None, port),
+        [host, port] => (Some(host.as_str()), port),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "usage: wsld connect [HOST] PORT",
+            ))
+        }
+    };
+    let port: u16 = port
+        .parse()
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid port {:?}", port)))?;
+
+    let mut server = connect_target(host, port).await?;
+
+    // EOF on stdin only half-closes the connection, so the response can still be received.
+    // Once the server closes its side, stdin is given a short grace period, like socat does.
+    let (server_r, server_w) = server.split();
+    let a = connect_stream(tokio::io::stdin(), server_w, |_| ());
+    let b = connect_stream(server_r, tokio::io::stdout(), |_| ());
+    tokio::pin!(a, b);
+    tokio::select! {
+        result = &mut a => {
+            result?;
+            b.await
+        }
+        result = &mut b => {
+            result?;
+            let _ = tokio::time::timeout(STDIN_GRACE, a).await;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use super::config::DnsConfig;
+use super::host;
+use super::limit::Limiter;
+use super::util::{either, read_frame, write_frame};
+
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const RESOLV_CONF_BACKUP: &str = "/etc/resolv.conf.wsld";
+const GENERATED_HEADER: &str = "# Generated by wsld\n";
+
+// Skip a possibly compressed domain name, returning the offset after it.
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)?;
+        if len & 0xC0 == 0xC0 {
+            return Some(pos + 2);
+        }
+        pos += 1 + len as usize;
+        if len == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+// Get the minimum TTL of records in the answer and authority sections of a response.
+fn min_ttl(msg: &[u8]) -> Option<u32> {
+    let count = |offset: usize| u16::from_be_bytes([msg[offset], msg[offset + 1]]);
+    if msg.len() < 12 {
+        return None;
+    }
+    let qdcount = count(4);
+    let rrcount = count(6) as usize + count(8) as usize;
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(msg, pos)? + 4;
+    }
+
+    let mut ttl = None;
+    for _ in 0..rrcount {
+        pos = skip_name(msg, pos)?;
+        let fields = msg.get(pos..pos + 10)?;
+        let record_ttl = u32::from_be_bytes([fields[4], fields[5], fields[6], fields[7]]);
+        let rdlength = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+        pos += 10 + rdlength;
+        ttl = Some(ttl.map_or(record_ttl, |ttl: u32| ttl.min(record_ttl)));
+    }
+    ttl
+}
+
+struct CacheEntry {
+    expiry: Instant,
+    response: Vec<u8>,
+}
+
+struct Cache {
+    // Keyed by the query without its ID.
+    entries: Mutex<HashMap<Vec<u8>, CacheEntry>>,
+    capacity: usize,
+}
+
+impl Cache {
+    fn get(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&query[2..])?;
+        if entry.expiry <= Instant::now() {
+            return None;
+        }
+        let mut response = entry.response.clone();
+        response[0..2].copy_from_slice(&query[0..2]);
+        Some(response)
+    }
+
+    fn insert(&self, query: &[u8], response: &[u8]) {
+        if self.capacity == 0 || response.len() < 12 {
+            return;
+        }
+
+        // Only cache successful responses and NXDOMAIN.
+        let rcode = response[3] & 0xF;
+        if rcode != 0 && rcode != 3 {
+            return;
+        }
+        let ttl = match min_ttl(response) {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expiry > now);
+            if entries.len() >= self.capacity {
+                return;
+            }
+        }
+        entries.insert(
+            query[2..].to_vec(),
+            CacheEntry {
+                expiry: Instant::now() + Duration::from_secs(ttl as u64),
+                response: response.to_vec(),
+            },
+        );
+    }
+}
+
+async fn query_host(query: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut server = host::request(b"dns\0").await?;
+    write_frame(&mut server, query).await?;
+    read_frame(&mut server)
+        .await?
+        .ok_or_else(|| ErrorKind::UnexpectedEof.into())
+}
+
+async fn resolve(cache: &Cache, query: &[u8]) -> std::io::Result<Vec<u8>> {
+    if query.len() < 12 {
+        return Err(Error::new(ErrorKind::InvalidData, "DNS query too short"));
+    }
+    if let Some(response) = cache.get(query) {
+        return Ok(response);
+    }
+    let response = query_host(query).await?;
+    cache.insert(query, &response);
+    Ok(response)
+}
+
+async fn serve_udp(socket: Arc<UdpSocket>, cache: Arc<Cache>) -> std::io::Result<()> {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (size, peer) = socket.recv_from(&mut buf).await?;
+        let query = buf[..size].to_vec();
+        let socket = socket.clone();
+        let cache = cache.clone();
+
+        tokio::task::spawn(async move {
+            match resolve(&cache, &query).await {
+                Ok(response) => {
+                    let _ = socket.send_to(&response, peer).await;
+                }
+                Err(err) => eprintln!("Failed to resolve: {}", err),
+            }
+        });
+    }
+}
+
+async fn handle_stream(mut stream: TcpStream, cache: Arc<Cache>) -> std::io::Result<()> {
+    while let Some(query) = read_frame(&mut stream).await? {
+        let response = resolve(&cache, &query).await?;
+        write_frame(&mut stream, &response).await?;
+    }
+    Ok(())
+}
+
+async fn serve_tcp(
+    listener: TcpListener,
+    limiter: Limiter,
+    cache: Arc<Cache>,
+) -> std::io::Result<()> {
+    loop {
+        let ((stream, _), permit) = limiter.accept(|| listener.accept()).await?;
+        let cache = cache.clone();
+
+        tokio::task::spawn(async move {
+            let _permit = permit;
+            if let Err(err) = handle_stream(stream, cache).await {
+                eprintln!("Failed to resolve: {}", err);
+            }
+        });
+    }
+}
+
+// Whether /etc/resolv.conf was written by wsld.
+fn resolv_conf_generated() -> bool {
+    std::fs::read_to_string(RESOLV_CONF)
+        .map(|content| content.starts_with(GENERATED_HEADER))
+        .unwrap_or(false)
+}
+
+// Put back the original /etc/resolv.conf from its backup, or remove it if there was none.
+fn restore_resolv_conf() -> std::io::Result<()> {
+    match std::fs::symlink_metadata(RESOLV_CONF_BACKUP) {
+        Ok(_) => std::fs::rename(RESOLV_CONF_BACKUP, RESOLV_CONF),
+        Err(err) if err.kind() == ErrorKind::NotFound => match std::fs::remove_file(RESOLV_CONF) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        },
+        Err(err) => Err(err),
+    }
+}
+
+/// Restore /etc/resolv.conf if a previous wsld was killed before it could restore it.
+fn repair_resolv_conf() -> std::io::Result<()> {
+    if resolv_conf_generated() {
+        warn!("restoring {} left over by a previous wsld", RESOLV_CONF);
+        restore_resolv_conf()?;
+    }
+    Ok(())
+}
+
+/// Points /etc/resolv.conf to us, and restores it when dropped. The original file (or symlink)
+/// is kept as a backup next to it, so it can be restored by a later run if wsld is killed.
+struct ResolvConfGuard;
+
+impl ResolvConfGuard {
+    fn install(nameserver: IpAddr) -> std::io::Result<Self> {
+        repair_resolv_conf()?;
+
+        match std::fs::remove_file(RESOLV_CONF_BACKUP) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+        match std::fs::read_link(RESOLV_CONF) {
+            Ok(target) => std::os::unix::fs::symlink(target, RESOLV_CONF_BACKUP)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            Err(_) => {
+                std::fs::copy(RESOLV_CONF, RESOLV_CONF_BACKUP)?;
+            }
+        }
+
+        // Replace rather than write through a symlink.
+        match std::fs::remove_file(RESOLV_CONF) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+        std::fs::write(
+            RESOLV_CONF,
+            format!("{}nameserver {}\n", GENERATED_HEADER, nameserver),
+        )?;
+        Ok(ResolvConfGuard)
+    }
+}
+
+impl Drop for ResolvConfGuard {
+    fn drop(&mut self) {
+        if let Err(err) = restore_resolv_conf() {
+            eprintln!("Cannot restore {}: {}", RESOLV_CONF, err);
+        }
+    }
+}
+
+pub async fn dns_forward(config: &'static DnsConfig) -> std::io::Result<()> {
+    let cache = Arc::new(Cache {
+        entries: Mutex::new(HashMap::new()),
+        capacity: config.cache_size,
+    });
+
+    let socket = Arc::new(UdpSocket::bind(config.listen).await?);
+    let listener = TcpListener::bind(config.listen).await?;
+    let limiter = Limiter::new(&config.limits);
+
+    let _guard = if config.rewrite_resolv_conf {
+        if config.listen.port() != 53 {
+            warn!("resolv.conf can only point to DNS servers listening on port 53");
+        }
+        info!("pointing {} to {}", RESOLV_CONF, config.listen.ip());
+        Some(ResolvConfGuard::install(config.listen.ip())?)
+    } else {
+        if let Err(err) = repair_resolv_conf() {
+            warn!("cannot restore {}: {}", RESOLV_CONF, err);
+        }
+        None
+    };
+
+    either(
+        serve_udp(socket, cache.clone()),
+        serve_tcp(listener, limiter, cache),
+    )
+    .await
+}
@@ -0,0 +1,67 @@
+use super::config::X11Display;
+use super::peercred;
+use super::util::shell_quote;
+use super::x11socket;
+use super::CONFIG;
+
+use std::io::{Error, ErrorKind};
+
+/// `wsld env`: print shell commands setting the environment variables for the services of
+/// wsld, for use as `eval "$(wsld env)"`.
+pub async fn env(args: &[String]) -> std::io::Result<()> {
+    if !args.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "usage: wsld env"));
+    }
+
+    // Prefer the display chosen by the running wsld, as it may have been picked automatically.
+    let display = match std::fs::read_to_string(x11socket::display_file(peercred::target_uid())) {
+        Ok(display) => Some(parse_display(&display)?),
+        Err(err) if err.kind() == ErrorKind::NotFound => match CONFIG.x11.first() {
+            Some(config) => match config.display {
+                X11Display::Number(display) => Some(display),
+                X11Display::Auto => None,
+            },
+            None => None,
+        },
+        Err(err) => return Err(err),
+    };
+    if let Some(display) = display {
+        println!("export DISPLAY=:{}", display);
+    }
+
+    if let Some(config) = &CONFIG.ssh_agent {
+        println!(
+            "export SSH_AUTH_SOCK={}",
+            shell_quote(&config.ssh_auth_sock)
+        );
+    }
+    Ok(())
+}
+
+// Parse the content of the display file, which is only trusted to be a display number.
+fn parse_display(content: &str) -> std::io::Result<u32> {
+    content
+        .trim()
+        .strip_prefix(':')
+        .and_then(|display| display.parse().ok())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid display {:?} in display file", content),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_parsing() {
+        assert_eq!(parse_display(":0\n").unwrap(), 0);
+        assert_eq!(parse_display(":12").unwrap(), 12);
+        assert!(parse_display("0").is_err());
+        assert!(parse_display(":0; rm -rf ~").is_err());
+        assert!(parse_display(":$(id)").is_err());
+    }
+}
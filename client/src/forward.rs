@@ -0,0 +1,90 @@
+use super::config::LimitConfig;
+use super::limit::Limiter;
+use super::tcp::forward_stream;
+
+use log::info;
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::net::TcpListener;
+
+// WSL forwards ports listened on 127.0.0.1 from Windows localhost, so listen on another
+// loopback address by default to avoid clashing with Windows services.
+const DEFAULT_LISTEN_ADDR: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+struct ForwardSpec {
+    listen: SocketAddr,
+    host: String,
+    port: u16,
+}
+
+// Parse `[LISTEN_ADDR:]LISTEN_PORT:HOST:PORT`, similar to ssh -L.
+fn parse_spec(spec: &str) -> Option<ForwardSpec> {
+    let (rest, port) = spec.rsplit_once(':')?;
+    let (listen, host) = rest.rsplit_once(':')?;
+    let listen = match listen.parse::<u16>() {
+        Ok(port) => SocketAddr::from((DEFAULT_LISTEN_ADDR, port)),
+        Err(_) => listen.parse().ok()?,
+    };
+    Some(ForwardSpec {
+        listen,
+        host: host.to_owned(),
+        port: port.parse().ok()?,
+    })
+}
+
+async fn forward_spec(spec: &'static ForwardSpec, listener: TcpListener) -> std::io::Result<()> {
+    // Only the global limits apply.
+    let limiter = Limiter::new(&LimitConfig::default());
+
+    loop {
+        let ((stream, peer), permit) = limiter.accept(|| listener.accept()).await?;
+        info!("{} connected to {}", peer, spec.listen);
+
+        tokio::task::spawn(async move {
+            let _permit = permit;
+            if let Err(err) = forward_stream(stream, Some(&spec.host), spec.port).await {
+                eprintln!("Failed to transfer: {}", err);
+            }
+        });
+    }
+}
+
+/// `wsld forward SPEC...`: forward connections to local addresses to Windows-side targets
+/// for as long as the command runs.
+pub async fn forward(args: &[String]) -> std::io::Result<()> {
+    if args.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "usage: wsld forward [LISTEN_ADDR:]LISTEN_PORT:HOST:PORT...",
+        ));
+    }
+
+    // Parse all specifications and bind all listeners first, so errors are reported early.
+    let mut listeners = Vec::new();
+    for arg in args {
+        let spec = parse_spec(arg).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid forward specification {:?}", arg),
+            )
+        })?;
+        let listener = TcpListener::bind(spec.listen).await?;
+        info!("forwarding {} to {}:{}", spec.listen, spec.host, spec.port);
+        listeners.push((spec, listener));
+    }
+
+    let mut tasks = Vec::new();
+    for (spec, listener) in listeners {
+        let spec: &'static ForwardSpec = Box::leak(Box::new(spec));
+        tasks.push(tokio::task::spawn(async move {
+            if let Err(err) = forward_spec(spec, listener).await {
+                eprintln!("Forwarder for {} error: {}", spec.listen, err);
+            }
+        }));
+    }
+
+    for task in tasks {
+        let _ = task.await;
+    }
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use super::vmsocket::VmSocket;
+use super::CONFIG;
+
+use hmac::{Hmac, Mac};
+use log::warn;
+use once_cell::sync::Lazy;
+use sha2::Sha256;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::process::exit;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const CHALLENGE_LEN: usize = 32;
+
+// How long to wait for each reply of wsldhost during authentication.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Replies to `auth` requests, followed by the challenge if one is sent.
+const AUTH_NOT_REQUIRED: u8 = 0;
+const AUTH_CHALLENGE: u8 = 1;
+
+// Result of checking the response to the challenge.
+const AUTH_SUCCESS: u8 = 0;
+
+static AUTH_KEY: Lazy<Option<Vec<u8>>> = Lazy::new(|| {
+    let path = CONFIG.auth_key_file.as_ref()?;
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.permissions().mode() & 0o077 != 0 => {
+            warn!("{:?} is accessible by other users", path);
+        }
+        _ => (),
+    }
+    match std::fs::read_to_string(path) {
+        Ok(key) => Some(key.trim().as_bytes().to_vec()),
+        Err(err) => {
+            eprintln!("cannot read {:?}: {}", path, err);
+            exit(1);
+        }
+    }
+});
+
+// Reads a reply from wsldhost during authentication, which should not take long.
+async fn auth_reply<T>(
+    reply: impl std::future::Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    match tokio::time::timeout(AUTH_TIMEOUT, reply).await {
+        Ok(Err(err)) if err.kind() == ErrorKind::UnexpectedEof => Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "wsldhost closed the connection, it may be too old to support authentication",
+        )),
+        Ok(result) => result,
+        Err(_) => Err(Error::new(
+            ErrorKind::TimedOut,
+            "wsldhost did not reply to authentication",
+        )),
+    }
+}
+
+// Answer the challenge of wsldhost with an HMAC-SHA256 keyed with the shared key.
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    key: &[u8],
+) -> std::io::Result<()> {
+    stream.write_all(b"auth").await?;
+    match auth_reply(stream.read_u8()).await? {
+        AUTH_CHALLENGE => (),
+        AUTH_NOT_REQUIRED => {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "auth_key_file is set, but wsldhost has no key configured",
+            ))
+        }
+        reply => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown authentication reply {}", reply),
+            ))
+        }
+    }
+
+    let mut challenge = [0; CHALLENGE_LEN];
+    auth_reply(stream.read_exact(&mut challenge)).await?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(&challenge);
+    stream.write_all(&mac.finalize().into_bytes()).await?;
+
+    match auth_reply(stream.read_u8()).await? {
+        AUTH_SUCCESS => Ok(()),
+        _ => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "wsldhost rejected the key in auth_key_file",
+        )),
+    }
+}
+
+/// Connect to wsldhost, authenticating first if a key is configured.
+pub async fn connect() -> std::io::Result<TcpStream> {
+    let mut stream = VmSocket::connect(CONFIG.service_port).await?;
+    if let Some(key) = &*AUTH_KEY {
+        authenticate(&mut stream, key).await?;
+    }
+    Ok(stream)
+}
+
+/// Check that authentication is configured the same way as on wsldhost, so a mismatch is
+/// reported instead of requests failing later.
+pub async fn check_auth() -> std::io::Result<()> {
+    let mut stream = connect().await?;
+    if AUTH_KEY.is_some() {
+        return stream.write_all(b"noop").await;
+    }
+
+    stream.write_all(b"auth").await?;
+    match auth_reply(stream.read_u8()).await {
+        Ok(AUTH_CHALLENGE) => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "wsldhost requires authentication, auth_key_file must be set",
+        )),
+        // Older versions of wsldhost close the connection, as they do not know `auth`.
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Connect to wsldhost and request function `func`.
+pub async fn request(func: &[u8; 4]) -> std::io::Result<TcpStream> {
+    let mut stream = connect().await?;
+    stream.write_all(func).await?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"shared key";
+
+    // Stands in for wsldhost handling an `auth` request, with `key` if configured.
+    async fn stand_in<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, key: Option<&[u8]>) {
+        let mut func = [0; 4];
+        stream.read_exact(&mut func).await.unwrap();
+        assert_eq!(&func, b"auth");
+
+        let key = match key {
+            Some(key) => key,
+            None => return stream.write_u8(AUTH_NOT_REQUIRED).await.unwrap(),
+        };
+        let challenge = [7; CHALLENGE_LEN];
+        stream.write_u8(AUTH_CHALLENGE).await.unwrap();
+        stream.write_all(&challenge).await.unwrap();
+
+        let mut response = [0; 32];
+        stream.read_exact(&mut response).await.unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(&challenge);
+        let result = match mac.verify_slice(&response) {
+            Ok(()) => AUTH_SUCCESS,
+            Err(_) => 1,
+        };
+        stream.write_u8(result).await.unwrap();
+    }
+
+    async fn authenticate_with(host_key: Option<&[u8]>) -> std::io::Result<()> {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (result, ()) = tokio::join!(authenticate(&mut client, KEY), stand_in(server, host_key));
+        result
+    }
+
+    #[tokio::test]
+    async fn auth_with_same_key() {
+        authenticate_with(Some(KEY)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn auth_with_wrong_key() {
+        let err = authenticate_with(Some(b"other key")).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn auth_not_configured_on_host() {
+        let err = authenticate_with(None).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn auth_unsupported_by_host() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        drop(server);
+        let err = authenticate(&mut client, KEY).await.unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe
+        ));
+    }
+}
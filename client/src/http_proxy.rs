@@ -0,0 +1,369 @@
+use super::config::HttpProxyConfig;
+use super::limit::Limiter;
+use super::tcp::{connect_host, STATUS_NOT_ALLOWED};
+use super::util::{connect_stream, either};
+
+use log::{info, warn};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+// Limit of the size of the request line and headers.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+// Limit of the size of a line of a chunked body other than data.
+const MAX_LINE_SIZE: u64 = 4096;
+
+// Read a request or response line and headers. Returns the head (without the empty line), and
+// leaves any data read past it in `buf`, which may also hold data read before.
+async fn read_head<R: AsyncRead + Unpin>(r: &mut R, buf: &mut Vec<u8>) -> std::io::Result<String> {
+    let mut chunk = vec![0u8; 4096];
+    let mut start = 0;
+    loop {
+        if let Some(pos) = buf[start..].windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(start + pos + 4);
+            buf.truncate(start + pos);
+            let head = std::mem::replace(buf, rest);
+            return String::from_utf8(head).map_err(|err| Error::new(ErrorKind::InvalidData, err));
+        }
+
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "request header too large",
+            ));
+        }
+
+        // The terminator might straddle chunks, so search from a bit before.
+        start = buf.len().saturating_sub(3);
+        let size = r.read(&mut chunk).await?;
+        if size == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..size]);
+    }
+}
+
+// How the end of a request body is found.
+#[derive(Debug, PartialEq)]
+enum Body {
+    Length(u64),
+    Chunked,
+}
+
+// Copy exactly `len` bytes from `r` to `w`.
+async fn copy_exact<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    r: &mut R,
+    w: &mut W,
+    len: u64,
+) -> std::io::Result<()> {
+    if tokio::io::copy(&mut r.take(len), w).await? < len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+// Copy a line of a chunked body other than data from `r` to `w`, and return it.
+async fn copy_line<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    r: &mut R,
+    w: &mut W,
+) -> std::io::Result<String> {
+    let mut line = String::new();
+    r.take(MAX_LINE_SIZE).read_line(&mut line).await?;
+    if !line.ends_with('\n') {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid chunked body"));
+    }
+    w.write_all(line.as_bytes()).await?;
+    Ok(line)
+}
+
+// Copy a request body framed as `body` from `r` to `w`.
+async fn copy_body<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    r: &mut R,
+    w: &mut W,
+    body: Body,
+) -> std::io::Result<()> {
+    if let Body::Length(len) = body {
+        return copy_exact(r, w, len).await;
+    }
+
+    loop {
+        let line = copy_line(r, w).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        // The chunk is followed by CRLF.
+        copy_exact(r, w, size + 2).await?;
+    }
+
+    // Trailers, up to the empty line.
+    while !copy_line(r, w).await?.trim_end().is_empty() {}
+    Ok(())
+}
+
+// Whether a header line is one of `names`.
+fn is_header(line: &str, names: &[&str]) -> bool {
+    let name = line.split(':').next().unwrap_or_default().trim();
+    names.iter().any(|header| name.eq_ignore_ascii_case(header))
+}
+
+// Relay the response from `r` to `w`, telling the client that the connection is closed
+// afterwards, as further requests on it are not forwarded.
+async fn relay_response<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    mut r: R,
+    mut w: W,
+) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    loop {
+        let head = read_head(&mut r, &mut buf).await?;
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().unwrap_or_default();
+        let interim = matches!(
+            status_line.split(' ').nth(1),
+            Some(status) if status.starts_with('1') && status != "101"
+        );
+
+        let mut response = format!("{}\r\n", status_line);
+        for line in lines {
+            if !interim && is_header(line, &["connection", "keep-alive", "proxy-connection"]) {
+                continue;
+            }
+            response.push_str(line);
+            response.push_str("\r\n");
+        }
+        if !interim {
+            response.push_str("Connection: close\r\n");
+        }
+        response.push_str("\r\n");
+        w.write_all(response.as_bytes()).await?;
+
+        if !interim {
+            break;
+        }
+    }
+    w.write_all(&buf).await?;
+    connect_stream(r, w, |_| ()).await
+}
+
+// Split `host:port`, `[v6]:port` or `host` into host and port.
+fn split_host_port(authority: &str, default_port: u16) -> Option<(&str, u16)> {
+    // Strip user info, if any.
+    let authority = authority.rsplit('@').next()?;
+
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        let port = match rest.strip_prefix(':') {
+            Some(port) => port.parse().ok()?,
+            None if rest.is_empty() => default_port,
+            None => return None,
+        };
+        return Some((host, port));
+    }
+
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host, port.parse().ok()?)),
+        None => Some((authority, default_port)),
+    }
+}
+
+async fn reply(stream: &mut TcpStream, status: &str) -> std::io::Result<()> {
+    stream
+        .write_all(format!("HTTP/1.1 {}\r\nConnection: close\r\n\r\n", status).as_bytes())
+        .await
+}
+
+async fn handle_stream(mut stream: TcpStream, peer: SocketAddr) -> std::io::Result<()> {
+    let mut rest = Vec::new();
+    let head = read_head(&mut stream, &mut rest).await?;
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => {
+            reply(&mut stream, "400 Bad Request").await?;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid request line {:?}", request_line),
+            ));
+        }
+    };
+
+    let connect = method == "CONNECT";
+    let (authority, path) = if connect {
+        (target, "")
+    } else if let Some(rest) = target.strip_prefix("http://") {
+        match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        }
+    } else {
+        // Only plain HTTP can be forwarded, HTTPS needs to use CONNECT.
+        reply(&mut stream, "400 Bad Request").await?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported request target {:?}", target),
+        ));
+    };
+
+    let (host, port) = match split_host_port(authority, if connect { 443 } else { 80 }) {
+        Some(v) => v,
+        None => {
+            reply(&mut stream, "400 Bad Request").await?;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid authority {:?}", authority),
+            ));
+        }
+    };
+
+    info!("{} {} {}:{}", peer, method, host, port);
+
+    let mut server = match connect_host(host, port).await {
+        Ok(Ok(server)) => server,
+        Ok(Err(status)) => {
+            warn!("cannot connect to {}:{}, status {}", host, port, status);
+            let response = match status {
+                STATUS_NOT_ALLOWED => "403 Forbidden",
+                _ => "502 Bad Gateway",
+            };
+            return reply(&mut stream, response).await;
+        }
+        Err(err) => {
+            reply(&mut stream, "502 Bad Gateway").await?;
+            return Err(err);
+        }
+    };
+
+    stream.set_nodelay(true)?;
+
+    if connect {
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+        server.write_all(&rest).await?;
+
+        let (client_r, client_w) = stream.split();
+        let (server_r, server_w) = server.split();
+        let a = connect_stream(client_r, server_w, |_| ());
+        let b = connect_stream(server_r, client_w, |_| ());
+        return either(a, b).await;
+    }
+
+    // Rewrite the request to origin-form. As the next request on the same connection might be
+    // for a different host, only this request is forwarded, and both the server and the client
+    // are told that the connection is closed afterwards.
+    let mut request = format!("{} {} {}\r\n", method, path, version);
+    let mut has_host = false;
+    let mut body = Body::Length(0);
+    for line in lines {
+        if is_header(
+            line,
+            &["connection", "proxy-connection", "proxy-authorization"],
+        ) {
+            continue;
+        }
+        let value = line.split_once(':').map_or("", |(_, value)| value.trim());
+        if is_header(line, &["transfer-encoding"]) && value.to_ascii_lowercase().contains("chunked")
+        {
+            body = Body::Chunked;
+        } else if is_header(line, &["content-length"]) && body != Body::Chunked {
+            match value.parse() {
+                Ok(len) => body = Body::Length(len),
+                Err(_) => {
+                    reply(&mut stream, "400 Bad Request").await?;
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid content length {:?}", value),
+                    ));
+                }
+            }
+        }
+        has_host |= is_header(line, &["host"]);
+        request.push_str(line);
+        request.push_str("\r\n");
+    }
+    if !has_host {
+        request.push_str(&format!("Host: {}\r\n", authority));
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    server.write_all(request.as_bytes()).await?;
+
+    let (client_r, client_w) = stream.split();
+    let (server_r, mut server_w) = server.split();
+    let mut client_r = BufReader::new(rest.as_slice().chain(client_r));
+    // Anything the client sends after the body is dropped.
+    let a = async {
+        copy_body(&mut client_r, &mut server_w, body).await?;
+        std::future::pending().await
+    };
+    let b = relay_response(server_r, client_w);
+    either(a, b).await
+}
+
+pub async fn http_proxy(config: &'static HttpProxyConfig) -> std::io::Result<()> {
+    let listener = TcpListener::bind(config.listen).await?;
+    let limiter = Limiter::new(&config.limits);
+
+    loop {
+        let ((stream, peer), permit) = limiter.accept(|| listener.accept()).await?;
+
+        tokio::task::spawn(async move {
+            let _permit = permit;
+            if let Err(err) = handle_stream(stream, peer).await {
+                eprintln!("Failed to transfer: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn body_with_length_stops_at_end() {
+        let mut r: &[u8] = b"hello, worldGET http://other/ HTTP/1.1\r\n\r\n";
+        let mut w = Vec::new();
+        copy_body(&mut r, &mut w, Body::Length(12)).await.unwrap();
+        assert_eq!(w, b"hello, world");
+    }
+
+    #[tokio::test]
+    async fn chunked_body_stops_at_end() {
+        let body: &[u8] = b"5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\n";
+        let mut r = [body, b"GET http://other/ HTTP/1.1\r\n\r\n"].concat();
+        let mut w = Vec::new();
+        copy_body(&mut r.as_slice(), &mut w, Body::Chunked)
+            .await
+            .unwrap();
+        assert_eq!(w, body);
+
+        r.truncate(body.len() - 10);
+        let mut w = Vec::new();
+        assert!(copy_body(&mut r.as_slice(), &mut w, Body::Chunked)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn response_closes_connection() {
+        let r: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n\
+                         HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\n\
+                         Content-Length: 2\r\n\r\nok";
+        let mut w = Vec::new();
+        relay_response(r, &mut w).await.unwrap();
+        assert_eq!(
+            w,
+            b"HTTP/1.1 100 Continue\r\n\r\n\
+              HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+        );
+    }
+}
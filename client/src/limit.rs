@@ -0,0 +1,129 @@
+use super::config::LimitConfig;
+use super::CONFIG;
+
+use log::warn;
+use once_cell::sync::Lazy;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// How long to pause accepting when running out of file descriptors or memory.
+const BACKOFF: Duration = Duration::from_millis(500);
+
+// Accept rate limits allow bursts of up to one second worth of connections.
+const BURST: Duration = Duration::from_secs(1);
+
+struct RateLimit {
+    interval: Duration,
+    // Theoretical arrival time of the next connection.
+    next: Mutex<Instant>,
+}
+
+impl RateLimit {
+    fn new(rate: u32) -> Option<Self> {
+        if rate == 0 {
+            return None;
+        }
+        Some(RateLimit {
+            interval: Duration::from_secs(1) / rate,
+            next: Mutex::new(Instant::now()),
+        })
+    }
+
+    async fn wait(&self) {
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = (*next).max(Instant::now());
+            *next = start + self.interval;
+            start
+        };
+        if let Some(deadline) = start.checked_sub(BURST) {
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+struct Limits {
+    connections: Option<Arc<Semaphore>>,
+    rate: Option<RateLimit>,
+}
+
+impl Limits {
+    fn new(config: &LimitConfig) -> Self {
+        Limits {
+            connections: config
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            rate: config.max_accept_rate.and_then(RateLimit::new),
+        }
+    }
+
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let permit = match &self.connections {
+            Some(semaphore) => Some(semaphore.clone().acquire_owned().await.unwrap()),
+            None => None,
+        };
+        if let Some(rate) = &self.rate {
+            rate.wait().await;
+        }
+        permit
+    }
+}
+
+// Limits shared by all services.
+static GLOBAL: Lazy<Limits> = Lazy::new(|| Limits::new(&CONFIG.limits));
+
+/// Held for as long as an accepted connection is open.
+pub struct Permit {
+    _global: Option<OwnedSemaphorePermit>,
+    _service: Option<OwnedSemaphorePermit>,
+}
+
+/// Limits the connections accepted by a service.
+pub struct Limiter {
+    service: Limits,
+}
+
+impl Limiter {
+    pub fn new(config: &LimitConfig) -> Self {
+        Limiter {
+            service: Limits::new(config),
+        }
+    }
+
+    /// Wait until both the service and global limits allow another connection.
+    pub async fn acquire(&self) -> Permit {
+        Permit {
+            _service: self.service.acquire().await,
+            _global: GLOBAL.acquire().await,
+        }
+    }
+
+    /// Accept a connection once both the service and global limits allow it. When out of file
+    /// descriptors, accepting is paused for a while instead of failing.
+    pub async fn accept<T, F, Fut>(&self, mut accept: F) -> std::io::Result<(T, Permit)>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = std::io::Result<T>>,
+    {
+        let permit = self.acquire().await;
+        loop {
+            match accept().await {
+                Ok(accepted) => return Ok((accepted, permit)),
+                Err(err) if is_resource_exhausted(&err) => {
+                    warn!("cannot accept connection: {}, pausing", err);
+                    tokio::time::sleep(BACKOFF).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+fn is_resource_exhausted(err: &std::io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    )
+}
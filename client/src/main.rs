@@ -1,23 +1,41 @@
 mod config;
+mod connect;
+mod dns;
+mod env;
+mod forward;
+mod host;
+mod http_proxy;
+mod limit;
+mod netfilter;
+mod peercred;
+mod pipe;
+mod procnet;
+mod reverse;
+mod socks;
 mod ssh_agent;
 mod tcp;
 mod time;
+mod udp;
+mod unix;
+mod unixsocket;
 mod util;
 mod vmsocket;
 mod x11;
+mod x11auth;
 mod x11socket;
 
 use config::Config;
-use vmsocket::VmSocket;
 
 use once_cell::sync::Lazy;
 use std::io::ErrorKind;
 use std::process::exit;
-use tokio::io::AsyncWriteExt;
+
+// Subcommands use the config file in the home directory.
+const SUBCOMMANDS: &[&str] = &["connect", "env", "forward"];
 
 static CONFIG: Lazy<Config> = Lazy::new(|| {
     let args: Vec<_> = std::env::args().collect();
-    let (config_path, home) = if args.len() == 2 {
+    let (config_path, home) = if args.len() == 2 && !SUBCOMMANDS.contains(&args[1].as_str()) {
         ({ args }.swap_remove(1).into(), false)
     } else {
         let mut config_path = dirs::home_dir().unwrap_or_else(|| {
@@ -32,7 +50,7 @@ static CONFIG: Lazy<Config> = Lazy::new(|| {
         Err(err) if err.kind() == ErrorKind::NotFound && home => {
             // If .wsld.toml isn't there, do its name: X11 forwarding
             return Config {
-                x11: Some(Default::default()),
+                x11: vec![Default::default()],
                 ..Default::default()
             };
         }
@@ -41,20 +59,19 @@ static CONFIG: Lazy<Config> = Lazy::new(|| {
             exit(1);
         }
     };
-    toml::from_str(&config_file).unwrap_or_else(|err| {
+    let mut config: Config = toml::from_str(&config_file).unwrap_or_else(|err| {
         eprintln!("invalid config file: {}", err);
         exit(1);
-    })
+    });
+    config.path = Some(config_path);
+    config
 });
 
 async fn wait_host_up() -> std::io::Result<()> {
     let mut retry = 5usize;
     loop {
-        match VmSocket::connect(CONFIG.service_port).await {
-            Ok(mut stream) => {
-                stream.write_all(b"noop").await?;
-                return Ok(());
-            }
+        match host::check_auth().await {
+            Ok(()) => return Ok(()),
             Err(err) if err.kind() == ErrorKind::TimedOut => {
                 if retry == 0 {
                     return Err(err);
@@ -66,11 +83,38 @@ async fn wait_host_up() -> std::io::Result<()> {
     }
 }
 
+async fn shutdown_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    util::either(interrupt.recv(), terminate.recv()).await;
+    Ok(())
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     env_logger::init();
 
+    let args: Vec<_> = std::env::args().collect();
+    let subcommand = match args.get(1).map(String::as_str) {
+        Some("connect") => Some(connect::connect(&args[2..]).await),
+        Some("env") => Some(env::env(&args[2..]).await),
+        Some("forward") => Some(forward::forward(&args[2..]).await),
+        _ => None,
+    };
+    if let Some(result) = subcommand {
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            exit(1);
+        }
+        // Exit straight away, as shutting down the runtime would wait for a pending blocking
+        // read of stdin.
+        exit(0);
+    }
+
     Lazy::force(&CONFIG);
+    peercred::warn_if_root_only();
 
     if let Err(err) = wait_host_up().await {
         eprintln!("Cannot connect to wsldhost: {}", err);
@@ -86,9 +130,9 @@ async fn main() {
         }));
     }
 
-    if let Some(config) = &CONFIG.x11 {
+    for (index, config) in CONFIG.x11.iter().enumerate() {
         tasks.push(tokio::task::spawn(async move {
-            if let Err(err) = x11::x11_forward(config).await {
+            if let Err(err) = x11::x11_forward(config, index == 0).await {
                 eprintln!("Failed to listen: {}", err);
             }
         }));
@@ -102,6 +146,14 @@ async fn main() {
         }));
     }
 
+    if let Some(config) = &CONFIG.udp_forward {
+        tasks.push(tokio::task::spawn(async move {
+            if let Err(err) = udp::udp_forward(config).await {
+                eprintln!("Udp forwarder error: {}", err);
+            }
+        }));
+    }
+
     if let Some(config) = &CONFIG.ssh_agent {
         tasks.push(tokio::task::spawn(async move {
             if let Err(err) = ssh_agent::ssh_agent_forward(config).await {
@@ -110,12 +162,71 @@ async fn main() {
         }));
     }
 
+    if let Some(config) = &CONFIG.socks {
+        tasks.push(tokio::task::spawn(async move {
+            if let Err(err) = socks::socks_proxy(config).await {
+                eprintln!("SOCKS proxy error: {}", err);
+            }
+        }));
+    }
+
+    if let Some(config) = &CONFIG.http_proxy {
+        tasks.push(tokio::task::spawn(async move {
+            if let Err(err) = http_proxy::http_proxy(config).await {
+                eprintln!("HTTP proxy error: {}", err);
+            }
+        }));
+    }
+
+    if let Some(config) = &CONFIG.dns {
+        tasks.push(tokio::task::spawn(async move {
+            if let Err(err) = dns::dns_forward(config).await {
+                eprintln!("DNS forwarder error: {}", err);
+            }
+        }));
+    }
+
+    for config in CONFIG.reverse_forward.iter() {
+        tasks.push(tokio::task::spawn(async move {
+            if let Err(err) = reverse::reverse_forward(config).await {
+                eprintln!("Reverse forwarder error: {}", err);
+            }
+        }));
+    }
+
+    for config in CONFIG.unix_forward.iter() {
+        tasks.push(tokio::task::spawn(async move {
+            if let Err(err) = unix::unix_forward(config).await {
+                eprintln!("Unix socket forwarder error: {}", err);
+            }
+        }));
+    }
+
+    for config in CONFIG.pipe_forward.iter() {
+        tasks.push(tokio::task::spawn(async move {
+            if let Err(err) = pipe::pipe_forward(config).await {
+                eprintln!("Named pipe forwarder error: {}", err);
+            }
+        }));
+    }
+
     // Return an error code if no task is running.
     if tasks.is_empty() {
         std::process::exit(1);
     }
 
-    for task in tasks {
-        let _ = task.await;
-    }
+    let wait_tasks = async {
+        for task in tasks {
+            let _ = task.await;
+        }
+    };
+    let wait_signal = async {
+        if let Err(err) = shutdown_signal().await {
+            eprintln!("Cannot listen for signals: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    // Returning from main drops all tasks, so they can clean up.
+    util::either(wait_tasks, wait_signal).await;
 }
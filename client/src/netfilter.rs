@@ -0,0 +1,143 @@
+use super::config::RedirectScope;
+use super::util::shell_quote;
+
+use std::io::Error;
+use std::net::Ipv4Addr;
+use std::process::Stdio;
+
+/// Run `iptables_cmd -t nat cmd`. If it fails, the error printed by iptables is returned.
+pub async fn execute_iptables(
+    iptables_cmd: &str,
+    cmd: &str,
+) -> std::io::Result<Result<(), String>> {
+    let mut p = tokio::process::Command::new("sh");
+    p.arg("-c");
+    p.arg(format!("{} -t nat {}", iptables_cmd, cmd));
+    p.stderr(Stdio::piped());
+    let output = p.output().await?;
+    Ok(if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_owned())
+    })
+}
+
+// Run `iptables_cmd -t nat cmd`, which is expected to succeed.
+async fn execute_iptables_checked(iptables_cmd: &str, cmd: &str) -> std::io::Result<()> {
+    execute_iptables(iptables_cmd, cmd)
+        .await?
+        .map_err(|err| Error::other(format!("{} -t nat {} failed: {}", iptables_cmd, cmd, err)))
+}
+
+// List the rules of `chain` in the nat table, in the format of `iptables -S`.
+async fn list_rules(iptables_cmd: &str, chain: &str) -> std::io::Result<Vec<String>> {
+    let mut p = tokio::process::Command::new("sh");
+    p.arg("-c");
+    p.arg(format!("{} -t nat -S {}", iptables_cmd, chain));
+    let output = p.output().await?;
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "{} -t nat -S {} failed: {}",
+            iptables_cmd,
+            chain,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_owned)
+        .collect())
+}
+
+// Name of the chain holding the jumps to `chain` for destinations other than loopback.
+fn destination_chain(chain: &str) -> String {
+    format!("{}-dst", chain)
+}
+
+/// Create `chain` in the nat table, or flush it if it exists already, and jump to it from
+/// OUTPUT for traffic sent to loopback, or to the destinations set with `set_destinations`.
+/// Traffic outside `scope` returns from the chain immediately, so redirect rules must be
+/// appended after this.
+pub async fn setup_chain(
+    iptables_cmd: &str,
+    chain: &str,
+    scope: &RedirectScope,
+) -> std::io::Result<()> {
+    let dst_chain = destination_chain(chain);
+    for chain in [chain, &dst_chain] {
+        let _ = execute_iptables(iptables_cmd, &format!("-N {}", chain)).await?;
+        execute_iptables_checked(iptables_cmd, &format!("-F {}", chain)).await?;
+    }
+
+    // Remove all jumps left by earlier runs, including jumps for destinations which are no
+    // longer in use.
+    for rule in list_rules(iptables_cmd, "OUTPUT").await? {
+        let jumps = rule.ends_with(&format!(" -j {}", chain))
+            || rule.ends_with(&format!(" -j {}", dst_chain));
+        let plain = rule
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || " ./:_-".contains(c));
+        if let (true, true, Some(spec)) = (jumps, plain, rule.strip_prefix("-A ")) {
+            execute_iptables_checked(iptables_cmd, &format!("-D {}", spec)).await?;
+        }
+    }
+    execute_iptables_checked(iptables_cmd, &format!("-I OUTPUT -o lo -j {}", chain)).await?;
+    execute_iptables_checked(iptables_cmd, &format!("-I OUTPUT -j {}", dst_chain)).await?;
+
+    // The scope comes from the config file, so quote it for the shell running iptables.
+    let mut exclusions = Vec::new();
+    if let Some(uid) = &scope.uid {
+        exclusions.push(format!("-m owner ! --uid-owner {}", shell_quote(uid)));
+    }
+    if let Some(gid) = &scope.gid {
+        exclusions.push(format!("-m owner ! --gid-owner {}", shell_quote(gid)));
+    }
+    if let Some(cgroup) = &scope.cgroup {
+        exclusions.push(format!("-m cgroup ! --path {}", shell_quote(cgroup)));
+    }
+    for exclusion in exclusions {
+        execute_iptables_checked(
+            iptables_cmd,
+            &format!("-A {} {} -j RETURN", chain, exclusion),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Jump to `chain` for traffic sent to `destinations`, replacing the destinations set before.
+pub async fn set_destinations(
+    iptables_cmd: &str,
+    chain: &str,
+    destinations: &[Ipv4Addr],
+) -> std::io::Result<()> {
+    let dst_chain = destination_chain(chain);
+    execute_iptables_checked(iptables_cmd, &format!("-F {}", dst_chain)).await?;
+    for destination in destinations {
+        execute_iptables_checked(
+            iptables_cmd,
+            &format!("-A {} -d {} -j {}", dst_chain, destination, chain),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Append (`op` = "-A") or delete (`op` = "-D") a rule redirecting `port` to `to_port`.
+pub async fn redirect_rule(
+    iptables_cmd: &str,
+    chain: &str,
+    op: &str,
+    protocol: &str,
+    port: u16,
+    to_port: u16,
+) -> std::io::Result<Result<(), String>> {
+    execute_iptables(
+        iptables_cmd,
+        &format!(
+            "{} {} -p {} --dport {} -j REDIRECT --to-port {}",
+            op, chain, protocol, port, to_port
+        ),
+    )
+    .await
+}
@@ -0,0 +1,100 @@
+use super::config::PeerConfig;
+use super::CONFIG;
+
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::os::unix::fs::MetadataExt;
+use tokio::net::UnixStream;
+
+// The user allowed when no UIDs or GIDs are configured: the user running wsld. When wsld runs as
+// root, this is the user who started it with sudo, or else the owner of the config file.
+static DEFAULT_UID: Lazy<Option<u32>> = Lazy::new(|| {
+    let euid = unsafe { libc::geteuid() };
+    if euid != 0 {
+        return Some(euid);
+    }
+
+    let sudo_uid = std::env::var("SUDO_UID")
+        .ok()
+        .and_then(|uid| uid.parse().ok());
+    let owner = || {
+        let path = CONFIG.path.as_ref()?;
+        std::fs::metadata(path).ok().map(|metadata| metadata.uid())
+    };
+    sudo_uid.or_else(owner).filter(|&uid| uid != 0)
+});
+
+/// The user wsld runs for, e.g. to publish files for: the default user, or root if there is
+/// none.
+pub fn target_uid() -> u32 {
+    DEFAULT_UID.unwrap_or_else(|| unsafe { libc::geteuid() })
+}
+
+/// Warn if services restricted to the default user can only be used by root, because wsld
+/// runs as root and the user it runs for cannot be found.
+pub fn warn_if_root_only() {
+    let mut peers = CONFIG
+        .x11
+        .iter()
+        .map(|config| &config.peers)
+        .chain(CONFIG.ssh_agent.iter().map(|config| &config.peers))
+        .chain(CONFIG.unix_forward.iter().map(|config| &config.peers))
+        .chain(CONFIG.pipe_forward.iter().map(|config| &config.peers));
+    if !peers.any(|peers| peers.allowed_uids.is_empty() && peers.allowed_gids.is_empty()) {
+        return;
+    }
+
+    match *DEFAULT_UID {
+        Some(uid) => info!("allowing uid {} to connect by default", uid),
+        None => eprintln!(
+            "Warning: wsld runs as root, so only root can connect to sockets without \
+             allowed_uids or allowed_gids set"
+        ),
+    }
+}
+
+/// Check the credentials of the peer of an accepted Unix socket connection against `config`.
+/// Root and, if no UIDs or GIDs are configured, the default user are always allowed.
+pub fn check(config: &PeerConfig, stream: &UnixStream) -> bool {
+    let cred = match stream.peer_cred() {
+        Ok(cred) => cred,
+        Err(err) => {
+            warn!("cannot get peer credentials: {}", err);
+            return false;
+        }
+    };
+
+    let allowed = if cred.uid() == 0 {
+        true
+    } else if config.allowed_uids.is_empty() && config.allowed_gids.is_empty() {
+        Some(cred.uid()) == *DEFAULT_UID
+    } else {
+        config.allowed_uids.contains(&cred.uid()) || config.allowed_gids.contains(&cred.gid())
+    };
+
+    if !allowed {
+        warn!(
+            "rejected connection from uid {} gid {} pid {}",
+            cred.uid(),
+            cred.gid(),
+            cred.pid()
+                .map_or("unknown".to_owned(), |pid| pid.to_string())
+        );
+    }
+    allowed
+}
+
+/// Describe the process at the other end of a Unix socket connection, for logging.
+pub fn describe(stream: &UnixStream) -> String {
+    let cred = match stream.peer_cred() {
+        Ok(cred) => cred,
+        Err(_) => return "unknown process".to_owned(),
+    };
+    match cred.pid() {
+        Some(pid) => match std::fs::read_link(format!("/proc/{}/exe", pid)) {
+            Ok(exe) => format!("pid {} ({}) uid {}", pid, exe.display(), cred.uid()),
+            Err(_) => format!("pid {} uid {}", pid, cred.uid()),
+        },
+        None => format!("uid {}", cred.uid()),
+    }
+}
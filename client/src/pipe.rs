@@ -0,0 +1,124 @@
+use super::config::PipeForwardConfig;
+use super::host;
+use super::unixsocket::serve_unix_socket;
+use super::util::{connect_stream, either};
+
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+// Ask wsldhost to open `pipe`, and check its reply.
+async fn request_pipe<S: AsyncRead + AsyncWrite + Unpin>(
+    server: &mut S,
+    pipe: &str,
+) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(5 + pipe.len());
+    buf.extend_from_slice(b"pipe");
+    buf.push(pipe.len() as u8);
+    buf.extend_from_slice(pipe.as_bytes());
+    server.write_all(&buf).await?;
+
+    match server.read_u8().await? {
+        0 => Ok(()),
+        2 => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("pipe {:?} is not allowed by wsldhost", pipe),
+        )),
+        _ => Err(Error::other(format!(
+            "wsldhost cannot open pipe {:?}",
+            pipe
+        ))),
+    }
+}
+
+async fn handle_stream(
+    config: &'static PipeForwardConfig,
+    mut stream: UnixStream,
+) -> std::io::Result<()> {
+    let mut server = host::connect().await?;
+    request_pipe(&mut server, &config.pipe).await?;
+
+    let (client_r, client_w) = stream.split();
+    let (server_r, server_w) = server.split();
+    let a = connect_stream(client_r, server_w, |_| ());
+    let b = connect_stream(server_r, client_w, |_| ());
+    either(a, b).await
+}
+
+pub async fn pipe_forward(config: &'static PipeForwardConfig) -> std::io::Result<()> {
+    if config.pipe.len() > u8::MAX as usize {
+        return Err(Error::new(ErrorKind::InvalidInput, "pipe name too long"));
+    }
+
+    serve_unix_socket(
+        &config.path,
+        config.mode,
+        &config.limits,
+        &config.peers,
+        |stream| handle_stream(config, stream),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stands in for wsldhost: reads one `pipe` request, and replies with `status`. Returns the
+    // name of the requested pipe.
+    async fn stand_in<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, status: u8) -> String {
+        let mut func = [0; 4];
+        stream.read_exact(&mut func).await.unwrap();
+        assert_eq!(&func, b"pipe");
+        let len = stream.read_u8().await.unwrap();
+        let mut name = vec![0; len as usize];
+        stream.read_exact(&mut name).await.unwrap();
+        stream.write_u8(status).await.unwrap();
+        String::from_utf8(name).unwrap()
+    }
+
+    async fn request_with_status(status: u8) -> (std::io::Result<()>, String) {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (result, name) = tokio::join!(
+            request_pipe(&mut client, "docker_engine"),
+            stand_in(server, status)
+        );
+        (result, name)
+    }
+
+    #[tokio::test]
+    async fn pipe_opened() {
+        let (result, name) = request_with_status(0).await;
+        assert_eq!(name, "docker_engine");
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pipe_failed() {
+        let (result, name) = request_with_status(1).await;
+        assert_eq!(name, "docker_engine");
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Other);
+    }
+
+    #[tokio::test]
+    async fn pipe_not_allowed() {
+        let (result, name) = request_with_status(2).await;
+        assert_eq!(name, "docker_engine");
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn pipe_request_encoding() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let stand_in = async {
+            let mut request = [0; 22];
+            server.read_exact(&mut request).await.unwrap();
+            server.write_u8(0).await.unwrap();
+            request
+        };
+        let (result, request) =
+            tokio::join!(request_pipe(&mut client, "openssh-ssh-agent"), stand_in);
+        result.unwrap();
+        assert_eq!(&request, b"pipe\x11openssh-ssh-agent");
+    }
+}
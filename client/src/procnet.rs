@@ -0,0 +1,69 @@
+use std::collections::HashSet;
+use std::io::{ErrorKind, Result};
+use std::net::Ipv4Addr;
+
+// TCP_LISTEN from include/net/tcp_states.h
+const TCP_LISTEN: u8 = 0x0A;
+
+fn parse_listening_ports(content: &str, ports: &mut HashSet<u16>) {
+    // Skip the header line. Each entry looks like
+    //    0: 0100007F:1F90 00000000:0000 0A ...
+    for line in content.lines().skip(1) {
+        let mut fields = line.split_whitespace();
+        let local = fields.nth(1);
+        let state = fields.nth(1);
+
+        let (local, state) = match (local, state) {
+            (Some(local), Some(state)) => (local, state),
+            _ => continue,
+        };
+
+        if u8::from_str_radix(state, 16) != Ok(TCP_LISTEN) {
+            continue;
+        }
+
+        let port = local
+            .rsplit_once(':')
+            .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+        if let Some(port) = port {
+            ports.insert(port);
+        }
+    }
+}
+
+/// Get the set of TCP ports currently being listened on inside WSL.
+///
+/// This is the same information WSL uses to decide whether a port should be
+/// forwarded from Windows localhost.
+pub fn tcp_listening_ports() -> Result<HashSet<u16>> {
+    let mut ports = HashSet::new();
+    for path in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        match std::fs::read_to_string(path) {
+            Ok(content) => parse_listening_ports(&content, &mut ports),
+            // tcp6 is missing if IPv6 is disabled.
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(ports)
+}
+
+/// Get the gateway of the default IPv4 route. In WSL2 this is the Windows host.
+pub fn default_gateway() -> Result<Option<Ipv4Addr>> {
+    let content = std::fs::read_to_string("/proc/net/route")?;
+
+    // Skip the header line. Each entry looks like
+    // eth0    00000000        0100A8C0        0003 ...
+    for line in content.lines().skip(1) {
+        let mut fields = line.split_whitespace();
+        let destination = fields.nth(1);
+        let gateway = fields.next();
+
+        if let (Some("00000000"), Some(gateway)) = (destination, gateway) {
+            if let Ok(gateway) = u32::from_str_radix(gateway, 16) {
+                return Ok(Some(Ipv4Addr::from(gateway.to_ne_bytes())));
+            }
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,73 @@
+use super::config::{ReverseForwardConfig, ReverseTarget};
+use super::host;
+use super::limit::Limiter;
+use super::util::{connect_stream, either};
+
+use log::info;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+
+// Delay before re-registering with wsldhost after the control stream is lost.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+async fn forward<S: AsyncRead + AsyncWrite>(local: S, id: u64) -> std::io::Result<()> {
+    let mut server = host::request(b"rvac").await?;
+    server.write_u64(id).await?;
+
+    let (client_r, client_w) = tokio::io::split(local);
+    let (server_r, server_w) = server.split();
+    let a = connect_stream(client_r, server_w, |_| ());
+    let b = connect_stream(server_r, client_w, |_| ());
+    either(a, b).await
+}
+
+async fn handle_connection(config: &'static ReverseForwardConfig, id: u64) -> std::io::Result<()> {
+    match &config.target {
+        ReverseTarget::Port(port) => {
+            let local = TcpStream::connect(("127.0.0.1", *port)).await?;
+            local.set_nodelay(true)?;
+            forward(local, id).await
+        }
+        ReverseTarget::Path(path) => forward(UnixStream::connect(path).await?, id).await,
+    }
+}
+
+async fn listen(config: &'static ReverseForwardConfig, limiter: &Limiter) -> std::io::Result<()> {
+    let mut control = host::request(b"rvsl").await?;
+    control.write_u16(config.port).await?;
+    if control.read_u8().await? != 0 {
+        return Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!("wsldhost cannot listen on port {}", config.port),
+        ));
+    }
+
+    info!("listening on Windows port {}", config.port);
+
+    loop {
+        // Connections not claimed in time are dropped by wsldhost.
+        let permit = limiter.acquire().await;
+        let id = control.read_u64().await?;
+
+        tokio::task::spawn(async move {
+            let _permit = permit;
+            if let Err(err) = handle_connection(config, id).await {
+                eprintln!("Failed to transfer: {}", err);
+            }
+        });
+    }
+}
+
+pub async fn reverse_forward(config: &'static ReverseForwardConfig) -> std::io::Result<()> {
+    let limiter = Limiter::new(&config.limits);
+
+    // Keep the listener registered even if wsldhost restarts.
+    loop {
+        if let Err(err) = listen(config, &limiter).await {
+            eprintln!("Reverse forwarder for port {} error: {}", config.port, err);
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
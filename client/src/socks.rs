@@ -0,0 +1,128 @@
+use super::config::SocksConfig;
+use super::limit::Limiter;
+use super::tcp::connect_host;
+use super::util::{connect_stream, either};
+
+use log::{info, warn};
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const REP_FAILURE: u8 = 1;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+async fn reply(stream: &mut TcpStream, rep: u8) -> std::io::Result<()> {
+    // We don't report the bound address, as it is on the Windows side and meaningless in WSL.
+    stream
+        .write_all(&[VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+async fn handle_stream(mut stream: TcpStream, peer: SocketAddr) -> std::io::Result<()> {
+    let version = stream.read_u8().await?;
+    if version != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported SOCKS version {}", version),
+        ));
+    }
+
+    let nmethods = stream.read_u8().await?;
+    let mut methods = vec![0; nmethods as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        stream.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "no acceptable authentication method",
+        ));
+    }
+    stream.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [_, cmd, _, atyp] = header;
+
+    let host = match atyp {
+        ATYP_IPV4 => {
+            let mut addr = [0; 4];
+            stream.read_exact(&mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut name = vec![0; len as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name).map_err(|err| Error::new(ErrorKind::InvalidData, err))?
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0; 16];
+            stream.read_exact(&mut addr).await?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        _ => {
+            reply(&mut stream, REP_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported address type {}", atyp),
+            ));
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    if cmd != CMD_CONNECT {
+        reply(&mut stream, REP_COMMAND_NOT_SUPPORTED).await?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported command {}", cmd),
+        ));
+    }
+
+    info!("{} connecting to {}:{}", peer, host, port);
+
+    let mut server = match connect_host(&host, port).await {
+        Ok(Ok(server)) => server,
+        Ok(Err(status)) => {
+            warn!("cannot connect to {}:{}, status {}", host, port, status);
+            return reply(&mut stream, status).await;
+        }
+        Err(err) => {
+            reply(&mut stream, REP_FAILURE).await?;
+            return Err(err);
+        }
+    };
+    reply(&mut stream, 0).await?;
+
+    stream.set_nodelay(true)?;
+
+    let (client_r, client_w) = stream.split();
+    let (server_r, server_w) = server.split();
+    let a = connect_stream(client_r, server_w, |_| ());
+    let b = connect_stream(server_r, client_w, |_| ());
+    either(a, b).await
+}
+
+pub async fn socks_proxy(config: &'static SocksConfig) -> std::io::Result<()> {
+    let listener = TcpListener::bind(config.listen).await?;
+    let limiter = Limiter::new(&config.limits);
+
+    loop {
+        let ((stream, peer), permit) = limiter.accept(|| listener.accept()).await?;
+
+        tokio::task::spawn(async move {
+            let _permit = permit;
+            if let Err(err) = handle_stream(stream, peer).await {
+                eprintln!("Failed to transfer: {}", err);
+            }
+        });
+    }
+}
@@ -1,14 +1,24 @@
-use super::config::TcpForwardConfig;
-use super::util::{connect_stream, either};
-use super::vmsocket::VmSocket;
-use super::CONFIG;
+use super::config::{ProxyProtocol, TcpForwardConfig};
+use super::host;
+use super::limit::Limiter;
+use super::netfilter;
+use super::procnet;
+use super::util::{connect_stream, either, sleep_opt, Activity};
 
-use log::{info, warn};
+use log::{debug, info, warn};
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::{HashMap, HashSet};
 use std::io::{Error, ErrorKind, Result as IoResult};
-use std::net::{self, SocketAddr};
-use tokio::io::AsyncWriteExt;
+use std::net::{self, IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 
+// Status replied by wsldhost to connection requests. These are the same as SOCKS5 reply codes.
+const STATUS_SUCCESS: u8 = 0;
+pub const STATUS_NOT_ALLOWED: u8 = 2;
+
 fn get_origin_dst(stream: &TcpStream) -> IoResult<SocketAddr> {
     use std::mem;
     use std::os::unix::io::AsRawFd;
@@ -56,7 +66,7 @@ fn get_origin_dst(stream: &TcpStream) -> IoResult<SocketAddr> {
 
 async fn handle_stream(
     config: &'static TcpForwardConfig,
-    mut stream: TcpStream,
+    stream: TcpStream,
     peer: SocketAddr,
 ) -> std::io::Result<()> {
     let local_addr = get_origin_dst(&stream)?;
@@ -70,63 +80,505 @@ async fn handle_stream(
         return Ok(());
     }
 
-    stream.set_nodelay(true)?;
+    if let Some(keepalive) = &config.keepalive {
+        SockRef::from(&stream).set_tcp_keepalive(
+            &TcpKeepalive::new()
+                .with_time(keepalive.time)
+                .with_interval(keepalive.interval)
+                .with_retries(keepalive.retries),
+        )?;
+    }
+
+    let options = config.port_options(port);
+    let timeouts = Timeouts {
+        idle: options
+            .and_then(|options| options.idle_timeout)
+            .or(config.idle_timeout),
+        lifetime: options.and_then(|options| options.max_lifetime),
+    };
 
-    let mut server = VmSocket::connect(CONFIG.service_port).await?;
-    server.write_all(b"tcp\0").await?;
-    server.write_u16(port).await?;
+    let server = match options.and_then(|options| options.proxy_protocol) {
+        Some(version) => connect_proxied(port, version, peer, local_addr).await?,
+        None => connect_target(None, port).await?,
+    };
+    transfer(stream, server, timeouts).await
+}
+
+fn write_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Connect to `port` on Windows localhost, and have wsldhost send a PROXY protocol header
+/// telling the service that the connection is from `source` to `destination`.
+async fn connect_proxied(
+    port: u16,
+    version: ProxyProtocol,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> std::io::Result<TcpStream> {
+    let mut buf = Vec::with_capacity(48);
+    buf.extend_from_slice(b"tcpp");
+    buf.extend_from_slice(&port.to_be_bytes());
+    buf.push(match version {
+        ProxyProtocol::V1 => 1,
+        ProxyProtocol::V2 => 2,
+    });
+    write_addr(&mut buf, source);
+    write_addr(&mut buf, destination);
+
+    let mut server = host::connect().await?;
+    server.write_all(&buf).await?;
+    check_status(server.read_u8().await?, "localhost", port)?;
+    Ok(server)
+}
+
+/// Forward `stream` to `port` on Windows localhost, or on `host` if specified.
+pub async fn forward_stream(
+    stream: TcpStream,
+    host: Option<&str>,
+    port: u16,
+) -> std::io::Result<()> {
+    let server = connect_target(host, port).await?;
+    transfer(stream, server, Timeouts::default()).await
+}
 
+#[derive(Clone, Copy)]
+enum CloseReason {
+    Completed,
+    Error,
+    Idle,
+    Lifetime,
+}
+
+// Number of forwarded connections closed for each reason, indexed by `CloseReason`.
+static CLOSED: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+fn record_close(reason: CloseReason) {
+    CLOSED[reason as usize].fetch_add(1, Ordering::Relaxed);
+    let count = |reason: CloseReason| CLOSED[reason as usize].load(Ordering::Relaxed);
+    debug!(
+        "connections closed: {} completed, {} errors, {} idle, {} reached max lifetime",
+        count(CloseReason::Completed),
+        count(CloseReason::Error),
+        count(CloseReason::Idle),
+        count(CloseReason::Lifetime),
+    );
+}
+
+#[derive(Default, Clone, Copy)]
+struct Timeouts {
+    idle: Option<Duration>,
+    lifetime: Option<Duration>,
+}
+
+async fn transfer(
+    mut stream: TcpStream,
+    mut server: TcpStream,
+    timeouts: Timeouts,
+) -> std::io::Result<()> {
+    stream.set_nodelay(true)?;
+
+    let activity = Activity::new();
     let (client_r, client_w) = stream.split();
     let (server_r, server_w) = server.split();
-    let a = connect_stream(client_r, server_w);
-    let b = connect_stream(server_r, client_w);
-    either(a, b).await
+    let a = connect_stream(client_r, server_w, |_| activity.touch());
+    let b = connect_stream(server_r, client_w, |_| activity.touch());
+    let idle = async {
+        match timeouts.idle {
+            Some(timeout) => activity.idle(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        result = either(a, b) => {
+            record_close(if result.is_ok() { CloseReason::Completed } else { CloseReason::Error });
+            result
+        }
+        _ = idle => {
+            record_close(CloseReason::Idle);
+            Ok(())
+        }
+        _ = sleep_opt(timeouts.lifetime) => {
+            record_close(CloseReason::Lifetime);
+            Ok(())
+        }
+    }
+}
+
+/// Connect to `port` on Windows localhost, or on `host` if specified.
+pub async fn connect_target(host: Option<&str>, port: u16) -> std::io::Result<TcpStream> {
+    match host {
+        None | Some("localhost") | Some("127.0.0.1") => {
+            let mut server = host::request(b"tcps").await?;
+            server.write_u16(port).await?;
+            check_status(server.read_u8().await?, "localhost", port)?;
+            Ok(server)
+        }
+        Some(host) => match connect_host(host, port).await? {
+            Ok(server) => Ok(server),
+            Err(status) => Err(check_status(status, host, port).unwrap_err()),
+        },
+    }
 }
 
-pub async fn execute_iptables(
+/// Turn the status replied by wsldhost to a connection request into a result.
+pub fn check_status(status: u8, host: &str, port: u16) -> std::io::Result<()> {
+    match status {
+        STATUS_SUCCESS => Ok(()),
+        STATUS_NOT_ALLOWED => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("connection to {}:{} is not allowed by wsldhost", host, port),
+        )),
+        status => Err(Error::other(format!(
+            "cannot connect to {}:{}, status {}",
+            host, port, status
+        ))),
+    }
+}
+
+/// Ask wsldhost to connect to `host`:`port` on behalf of us. The host name is resolved on
+/// Windows. If the connection fails, the status code replied by wsldhost is returned, which
+/// is the same as SOCKS5 reply codes.
+pub async fn connect_host(host: &str, port: u16) -> std::io::Result<Result<TcpStream, u8>> {
+    let len: u8 = host
+        .len()
+        .try_into()
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "host name too long"))?;
+
+    let mut server = host::connect().await?;
+    let mut buf = Vec::with_capacity(8 + host.len());
+    buf.extend_from_slice(b"tcph");
+    buf.push(len);
+    buf.extend_from_slice(host.as_bytes());
+    buf.extend_from_slice(&port.to_be_bytes());
+    server.write_all(&buf).await?;
+
+    match server.read_u8().await? {
+        STATUS_SUCCESS => Ok(Ok(server)),
+        status => Ok(Err(status)),
+    }
+}
+
+async fn redirect_rule(
     config: &'static TcpForwardConfig,
-    cmd: &str,
-) -> std::io::Result<Result<(), ()>> {
-    let mut p = tokio::process::Command::new("sh");
-    p.arg("-c");
-    p.arg(format!("{} -t nat {}", config.iptables_cmd, cmd));
-    let mut child = p.spawn()?;
-    let exit = child.wait().await?;
-    Ok(if exit.success() { Ok(()) } else { Err(()) })
+    op: &str,
+    port: u16,
+) -> std::io::Result<Result<(), String>> {
+    netfilter::redirect_rule(
+        &config.iptables_cmd,
+        "wsld",
+        op,
+        "tcp",
+        port,
+        config.service_port,
+    )
+    .await
 }
 
-pub async fn tcp_forward(config: &'static TcpForwardConfig) -> std::io::Result<()> {
-    let listener = TcpListener::bind(("127.0.0.1", config.service_port)).await?;
+async fn host_listening_ports() -> std::io::Result<HashSet<u16>> {
+    let mut server = host::request(b"tcpl").await?;
+    read_listening_ports(&mut server).await
+}
+
+async fn read_listening_ports<R: AsyncRead + Unpin>(r: &mut R) -> std::io::Result<HashSet<u16>> {
+    let count = r.read_u16().await?;
+    let mut ports = HashSet::with_capacity(count as usize);
+    for _ in 0..count {
+        ports.insert(r.read_u16().await?);
+    }
+    Ok(ports)
+}
+
+// Longest delay between retries of a failing iptables command.
+const MAX_REDIRECT_BACKOFF: Duration = Duration::from_secs(300);
+
+// Ports whose iptables command failed, which are retried with exponential backoff. Each failing
+// port is only warned about once, until it succeeds.
+#[derive(Default)]
+struct RedirectFailures {
+    ports: HashMap<u16, (Instant, Duration)>,
+}
+
+impl RedirectFailures {
+    fn ready(&self, port: u16) -> bool {
+        self.ports
+            .get(&port)
+            .is_none_or(|(next, _)| Instant::now() >= *next)
+    }
+
+    fn failed(&mut self, port: u16, initial: Duration, err: &str) {
+        match self.ports.get_mut(&port) {
+            Some((next, delay)) => {
+                *delay = (*delay * 2).min(MAX_REDIRECT_BACKOFF);
+                *next = Instant::now() + *delay;
+            }
+            None => {
+                warn!(
+                    "cannot update redirect of port {}, will retry: {}",
+                    port, err
+                );
+                self.ports.insert(port, (Instant::now() + initial, initial));
+            }
+        }
+    }
+
+    fn succeeded(&mut self, port: u16) {
+        if self.ports.remove(&port).is_some() {
+            info!("updated redirect of port {}", port);
+        }
+    }
+}
 
-    let _ = execute_iptables(config, "-N wsld").await?;
-    execute_iptables(config, "-F wsld").await?.unwrap();
-    let _ = execute_iptables(config, "-D OUTPUT -o lo -j wsld").await?;
-    execute_iptables(config, "-I OUTPUT -o lo -j wsld")
-        .await?
-        .unwrap();
-
-    for &port in config.ports.iter() {
-        execute_iptables(
-            config,
-            &format!(
-                "-A wsld -p tcp --dport {} -j REDIRECT --to-port {}",
-                port, config.service_port
-            ),
-        )
-        .await?
-        .unwrap();
-    }
-    execute_iptables(config, "-A wsld -j RETURN")
-        .await?
-        .unwrap();
+// Keep the set of REDIRECT rules in sync with the ports to forward. Ports that are being
+// listened on inside WSL are skipped, so a local server takes precedence.
+async fn sync_redirects(config: &'static TcpForwardConfig) -> std::io::Result<()> {
+    let mut redirected = HashSet::new();
+    let mut conflicts = HashSet::new();
+    let mut mirrored = HashSet::new();
+    let mut last_mirror: Option<Instant> = None;
+    let mut failures = RedirectFailures::default();
+    let mut destinations = None;
 
     loop {
-        let (stream, peer) = listener.accept().await?;
+        // The Windows host's addresses change with the network, e.g. after sleep.
+        let wanted_destinations = match redirect_destinations(config) {
+            Ok(wanted) => Some(wanted),
+            Err(err) => {
+                warn!("cannot find Windows host addresses: {}", err);
+                None
+            }
+        };
+        if let Some(wanted) =
+            wanted_destinations.filter(|wanted| destinations.as_ref() != Some(wanted))
+        {
+            for addr in wanted
+                .iter()
+                .filter(|&addr| !config.virtual_ips.contains(addr))
+            {
+                info!("intercepting connections to Windows host {}", addr);
+            }
+            match netfilter::set_destinations(&config.iptables_cmd, "wsld", &wanted).await {
+                Ok(()) => destinations = Some(wanted),
+                Err(err) => warn!("cannot update intercepted addresses: {}", err),
+            }
+        }
+
+        if let Some(mirror) = &config.mirror {
+            if last_mirror.is_none_or(|time| time.elapsed() >= mirror.interval) {
+                match host_listening_ports().await {
+                    Ok(ports) => {
+                        mirrored = ports
+                            .into_iter()
+                            .filter(|&port| mirror.matches(port))
+                            .collect()
+                    }
+                    Err(err) => warn!("cannot get listening ports from wsldhost: {}", err),
+                }
+                last_mirror = Some(Instant::now());
+            }
+        }
+
+        let listening = procnet::tcp_listening_ports()?;
+
+        let mut wanted: HashSet<u16> = config
+            .ports
+            .iter()
+            .chain(mirrored.iter())
+            .copied()
+            .filter(|&port| port != config.service_port)
+            .collect();
+
+        conflicts.retain(|port| wanted.contains(port));
+        for &port in wanted.iter() {
+            if listening.contains(&port) {
+                if conflicts.insert(port) {
+                    warn!("port {} is listened on inside WSL, not redirecting", port);
+                }
+            } else if conflicts.remove(&port) {
+                info!("port {} is no longer listened on inside WSL", port);
+            }
+        }
+        wanted.retain(|port| !conflicts.contains(port));
+
+        let added: Vec<u16> = wanted.difference(&redirected).copied().collect();
+        let removed: Vec<u16> = redirected.difference(&wanted).copied().collect();
+
+        // Ports whose command failed are not retried before their backoff elapses.
+        failures
+            .ports
+            .retain(|port, _| wanted.contains(port) != redirected.contains(port));
+
+        for port in removed {
+            if !failures.ready(port) {
+                continue;
+            }
+            info!("stop redirecting port {}", port);
+            match redirect_rule(config, "-D", port).await? {
+                Ok(()) => {
+                    redirected.remove(&port);
+                    failures.succeeded(port);
+                }
+                Err(err) => failures.failed(port, config.listener_check_interval, &err),
+            }
+        }
+
+        for port in added {
+            if !failures.ready(port) {
+                continue;
+            }
+            info!("redirecting port {}", port);
+            match redirect_rule(config, "-A", port).await? {
+                Ok(()) => {
+                    redirected.insert(port);
+                    failures.succeeded(port);
+                }
+                Err(err) => failures.failed(port, config.listener_check_interval, &err),
+            }
+        }
+
+        tokio::time::sleep(config.listener_check_interval).await;
+    }
+}
+
+// Addresses other than loopback whose connections are redirected.
+fn redirect_destinations(config: &TcpForwardConfig) -> std::io::Result<Vec<Ipv4Addr>> {
+    let mut destinations = config.virtual_ips.clone();
+    if config.host_ip {
+        for addr in windows_host_ips()? {
+            if !destinations.contains(&addr) {
+                destinations.push(addr);
+            }
+        }
+    }
+    Ok(destinations)
+}
+
+// Find addresses which tools may use to reach the Windows host: the default gateway, the
+// nameservers in resolv.conf if they're not local, and host.docker.internal in /etc/hosts.
+fn windows_host_ips() -> std::io::Result<Vec<Ipv4Addr>> {
+    let mut addrs = Vec::new();
+    if let Some(gateway) = procnet::default_gateway()? {
+        addrs.push(gateway);
+    }
+
+    let resolv_conf = match std::fs::read_to_string("/etc/resolv.conf") {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    for line in resolv_conf.lines() {
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some("nameserver") {
+            continue;
+        }
+        if let Some(Ok(addr)) = fields.next().map(str::parse::<Ipv4Addr>) {
+            if !addr.is_loopback() && !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+
+    let hosts = match std::fs::read_to_string("/etc/hosts") {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    for addr in hosts_lookup(&hosts, "host.docker.internal") {
+        if !addr.is_loopback() && !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    Ok(addrs)
+}
+
+// Find the IPv4 addresses of `name` in the content of /etc/hosts.
+fn hosts_lookup(hosts: &str, name: &str) -> Vec<Ipv4Addr> {
+    hosts
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let addr = fields.next()?.parse().ok()?;
+            fields
+                .any(|field| field.eq_ignore_ascii_case(name))
+                .then_some(addr)
+        })
+        .collect()
+}
+
+async fn accept_loop(
+    config: &'static TcpForwardConfig,
+    listener: TcpListener,
+) -> std::io::Result<()> {
+    let limiter = Limiter::new(&config.limits);
+
+    loop {
+        let ((stream, peer), permit) = limiter.accept(|| listener.accept()).await?;
 
         tokio::task::spawn(async move {
+            let _permit = permit;
             if let Err(err) = handle_stream(config, stream, peer).await {
                 eprintln!("Failed to transfer: {}", err);
             }
         });
     }
 }
+
+pub async fn tcp_forward(config: &'static TcpForwardConfig) -> std::io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", config.service_port)).await?;
+
+    netfilter::setup_chain(&config.iptables_cmd, "wsld", &config.scope).await?;
+
+    either(accept_loop(config, listener), sync_redirects(config)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn listening_ports_reply() {
+        let mut reply: &[u8] = &[0, 3, 0, 22, 0x0b, 0xb8, 0x1f, 0x90];
+        let ports = read_listening_ports(&mut reply).await.unwrap();
+        assert_eq!(ports, HashSet::from([22, 3000, 8080]));
+
+        let mut reply: &[u8] = &[0, 0];
+        assert!(read_listening_ports(&mut reply).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn hosts_file_lookup() {
+        let hosts = "127.0.0.1 localhost\n\
+                     # 10.0.0.1 host.docker.internal\n\
+                     192.168.1.5 host.docker.internal gateway.docker.internal # Docker\n\
+                     ::1 host.docker.internal\n";
+        assert_eq!(
+            hosts_lookup(hosts, "host.docker.internal"),
+            [Ipv4Addr::new(192, 168, 1, 5)]
+        );
+        assert!(hosts_lookup(hosts, "example.com").is_empty());
+    }
+
+    #[tokio::test]
+    async fn listening_ports_truncated_reply() {
+        let mut reply: &[u8] = &[0, 2, 0, 22];
+        assert!(read_listening_ports(&mut reply).await.is_err());
+    }
+}
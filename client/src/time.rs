@@ -1,13 +1,12 @@
 use super::config::TimeConfig;
-use super::vmsocket::VmSocket;
-use super::CONFIG;
+use super::host;
 
 use std::io::{Error, ErrorKind};
 use std::time::{Duration, SystemTime};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 async fn sync_time() -> std::io::Result<()> {
-    let mut stream = VmSocket::connect(CONFIG.service_port).await?;
+    let mut stream = host::connect().await?;
     let start = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap();
@@ -57,7 +56,7 @@ async fn sync_time() -> std::io::Result<()> {
     let diff_str = format!(
         "{}{}",
         if diff < 0 { "-" } else { "" },
-        humantime::format_duration(Duration::from_micros(diff.abs() as u64))
+        humantime::format_duration(Duration::from_micros(diff.unsigned_abs()))
     );
     eprintln!(
         "Received time {}, clock off by {}, {}",
@@ -0,0 +1,189 @@
+use super::config::UdpForwardConfig;
+use super::host;
+use super::netfilter;
+use super::tcp;
+use super::util::{either, read_frame, write_frame, Activity};
+
+use log::info;
+use std::collections::HashMap;
+use std::io::{Error, Result as IoResult};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+// Number of datagrams to queue per session before dropping.
+const SESSION_QUEUE: usize = 64;
+
+async fn handle_session(
+    config: &'static UdpForwardConfig,
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    port: u16,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+) -> IoResult<()> {
+    let mut server = host::request(b"udp\0").await?;
+    server.write_u16(port).await?;
+    tcp::check_status(server.read_u8().await?, "localhost", port)?;
+
+    let activity = Activity::new();
+    let (mut server_r, mut server_w) = server.split();
+    let a = async {
+        while let Some(datagram) = rx.recv().await {
+            activity.touch();
+            write_frame(&mut server_w, &datagram).await?;
+        }
+        Ok(())
+    };
+    let b = async {
+        while let Some(datagram) = read_frame(&mut server_r).await? {
+            activity.touch();
+            socket.send_to(&datagram, peer).await?;
+        }
+        Ok(())
+    };
+    let idle = async {
+        activity.idle(config.idle_timeout).await;
+        info!("session {} to {} idle, closing", peer, port);
+        Ok(())
+    };
+    either(either(a, b), idle).await
+}
+
+// Receive datagrams sent to one forwarded port, and hand those from each source address to
+// their own session, started with `start_session`.
+async fn dispatch<F>(socket: &UdpSocket, mut start_session: F) -> IoResult<()>
+where
+    F: FnMut(SocketAddr, mpsc::Receiver<Vec<u8>>),
+{
+    let mut sessions: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut buf = vec![0u8; 65536];
+
+    loop {
+        let (size, peer) = socket.recv_from(&mut buf).await?;
+
+        if sessions.get(&peer).is_none_or(|tx| tx.is_closed()) {
+            // Clean up sessions that have ended before creating a new one.
+            sessions.retain(|_, tx| !tx.is_closed());
+
+            let (tx, rx) = mpsc::channel(SESSION_QUEUE);
+            start_session(peer, rx);
+            sessions.insert(peer, tx);
+        }
+
+        // Like UDP itself, drop the datagram if we cannot keep up.
+        let _ = sessions[&peer].try_send(buf[..size].to_vec());
+    }
+}
+
+async fn forward_port(
+    config: &'static UdpForwardConfig,
+    socket: UdpSocket,
+    port: u16,
+) -> IoResult<()> {
+    let socket = Arc::new(socket);
+    dispatch(&socket, |peer, rx| {
+        info!("{} started session to {}", peer, port);
+        let socket = socket.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = handle_session(config, socket, peer, port, rx).await {
+                eprintln!("Failed to transfer: {}", err);
+            }
+        });
+    })
+    .await
+}
+
+pub async fn udp_forward(config: &'static UdpForwardConfig) -> std::io::Result<()> {
+    netfilter::setup_chain(&config.iptables_cmd, "wsld-udp", &config.scope).await?;
+
+    // REDIRECT rewrites the destination of datagrams before they are received, so each port is
+    // redirected to a socket of its own, which tells which port a datagram was sent to.
+    let mut tasks = Vec::new();
+    for &port in config.ports.iter() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let local_port = socket.local_addr()?.port();
+        netfilter::redirect_rule(
+            &config.iptables_cmd,
+            "wsld-udp",
+            "-A",
+            "udp",
+            port,
+            local_port,
+        )
+        .await?
+        .map_err(|err| Error::other(format!("cannot redirect UDP port {}: {}", port, err)))?;
+
+        tasks.push(tokio::task::spawn(async move {
+            if let Err(err) = forward_port(config, socket, port).await {
+                eprintln!("Udp forwarder error on port {}: {}", port, err);
+            }
+        }));
+    }
+
+    for task in tasks {
+        let _ = task.await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    // Stands in for the redirected socket of a forwarded port, reporting each session started
+    // on it together with the port it forwards to.
+    async fn stand_in(
+        port: u16,
+        sessions: mpsc::UnboundedSender<(u16, SocketAddr, Vec<u8>)>,
+    ) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            dispatch(&socket, |peer, mut rx| {
+                let sessions = sessions.clone();
+                tokio::task::spawn(async move {
+                    while let Some(datagram) = rx.recv().await {
+                        sessions.send((port, peer, datagram)).unwrap();
+                    }
+                });
+            })
+            .await
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn datagrams_are_dispatched_by_receiving_socket() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let syslog = stand_in(514, tx.clone()).await;
+        let statsd = stand_in(8125, tx).await;
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        client.send_to(b"first", syslog).await.unwrap();
+        client.send_to(b"second", syslog).await.unwrap();
+        client.send_to(b"third", statsd).await.unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            let datagram = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            received.push(datagram);
+        }
+        received.sort();
+        assert_eq!(
+            received,
+            [
+                (514, client_addr, b"first".to_vec()),
+                (514, client_addr, b"second".to_vec()),
+                (8125, client_addr, b"third".to_vec()),
+            ]
+        );
+    }
+}
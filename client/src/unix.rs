@@ -0,0 +1,30 @@
+use super::config::UnixForwardConfig;
+use super::tcp::connect_target;
+use super::unixsocket::serve_unix_socket;
+use super::util::{connect_stream, either};
+
+use tokio::net::UnixStream;
+
+async fn handle_stream(
+    config: &'static UnixForwardConfig,
+    mut stream: UnixStream,
+) -> std::io::Result<()> {
+    let mut server = connect_target(None, config.target).await?;
+
+    let (client_r, client_w) = stream.split();
+    let (server_r, server_w) = server.split();
+    let a = connect_stream(client_r, server_w, |_| ());
+    let b = connect_stream(server_r, client_w, |_| ());
+    either(a, b).await
+}
+
+pub async fn unix_forward(config: &'static UnixForwardConfig) -> std::io::Result<()> {
+    serve_unix_socket(
+        &config.path,
+        config.mode,
+        &config.limits,
+        &config.peers,
+        |stream| handle_stream(config, stream),
+    )
+    .await
+}
@@ -0,0 +1,82 @@
+use super::config::{LimitConfig, PeerConfig};
+use super::limit::Limiter;
+use super::peercred;
+
+use std::fs::Permissions;
+use std::future::Future;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tokio::net::{UnixListener, UnixStream};
+
+// Bind `path` with permissions `mode`. The umask is set for the bind, so the socket is not
+// accessible to others before its permissions are set.
+fn bind(path: &str, mode: u32) -> std::io::Result<UnixListener> {
+    if let Some(parent) = Path::new(path).parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    // Remove existing socket
+    let _ = std::fs::remove_file(path);
+
+    let umask = unsafe { libc::umask(!mode & 0o777) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+
+    std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Listen on the Unix socket `path` with permissions `mode`, and run `handler` for each
+/// connection accepted within `limits` from a peer allowed by `peers`.
+pub async fn serve_unix_socket<F, Fut>(
+    path: &str,
+    mode: u32,
+    limits: &LimitConfig,
+    peers: &PeerConfig,
+    handler: F,
+) -> std::io::Result<()>
+where
+    F: Fn(UnixStream) -> Fut,
+    Fut: Future<Output = std::io::Result<()>> + Send + 'static,
+{
+    let listener = bind(path, mode)?;
+    let limiter = Limiter::new(limits);
+
+    loop {
+        let ((stream, _), permit) = limiter.accept(|| listener.accept()).await?;
+        if !peercred::check(peers, &stream) {
+            continue;
+        }
+
+        let transfer = handler(stream);
+        tokio::task::spawn(async move {
+            let _permit = permit;
+            if let Err(err) = transfer.await {
+                eprintln!("Failed to transfer: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn socket_is_bound_with_mode() {
+        let dir = std::env::temp_dir().join(format!("wsld-unixsocket-test-{}", std::process::id()));
+        let path = dir.join("sub").join("socket");
+        let path = path.to_str().unwrap();
+
+        let _listener = bind(path, 0o600).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // An existing socket is replaced.
+        let _listener = bind(path, 0o660).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
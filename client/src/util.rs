@@ -1,4 +1,6 @@
 use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub async fn either<T, A: Future<Output = T>, B: Future<Output = T>>(a: A, b: B) -> T {
@@ -8,9 +10,12 @@ pub async fn either<T, A: Future<Output = T>, B: Future<Output = T>>(a: A, b: B)
     }
 }
 
+/// Copy `r` to `w` until EOF, then shut down `w`. `progress` is called with the number of
+/// bytes of each chunk transferred.
 pub async fn connect_stream<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
     mut r: R,
     mut w: W,
+    mut progress: impl FnMut(usize),
 ) -> std::io::Result<()> {
     let mut buf = vec![0u8; 4096];
     loop {
@@ -18,7 +23,84 @@ pub async fn connect_stream<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
         if size == 0 {
             break;
         }
+        progress(size);
         w.write_all(&buf[0..size]).await?;
     }
     w.shutdown().await
 }
+
+/// Quote `value` as a single word for POSIX shells.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Resolve after `duration`, or never if it is `None`.
+pub async fn sleep_opt(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => tokio::time::sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Read a datagram framed with a big-endian 16-bit length. Returns `None` on EOF.
+pub async fn read_frame<R: AsyncRead + Unpin>(r: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let len = match r.read_u16().await {
+        Ok(len) => len,
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf).await?;
+    Ok(Some(buf))
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(w: &mut W, data: &[u8]) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(2 + data.len());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+    w.write_all(&buf).await
+}
+
+/// Tracks when a connection was last active, for idle timeouts.
+pub struct Activity {
+    start: Instant,
+    // Milliseconds since `start`.
+    last: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    pub fn touch(&self) {
+        self.last
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Resolve once there is no activity for `timeout`.
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let deadline = last + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_quoting() {
+        assert_eq!(shell_quote("/tmp/.wsld/ssh"), "'/tmp/.wsld/ssh'");
+        assert_eq!(shell_quote("a'b"), "'a'\\''b'");
+    }
+}
@@ -1,34 +1,203 @@
-use super::config::X11Config;
+use super::config::{X11Config, X11Display};
+use super::host;
+use super::limit::Limiter;
+use super::peercred;
 use super::util::{connect_stream, either};
-use super::vmsocket::VmSocket;
-use super::x11socket::X11Lock;
-use super::CONFIG;
+use super::x11auth::{FakeCookie, SetupRequest};
+use super::x11socket::{PublishedDisplay, X11Lock};
 
-use tokio::io::AsyncWriteExt;
-use tokio::net::UnixStream;
+use log::{info, warn};
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
 
-async fn handle_stream(mut stream: UnixStream) -> std::io::Result<()> {
-    let mut server = VmSocket::connect(CONFIG.service_port).await?;
-    server.write_all(b"x11\0").await?;
+// How long X11 clients have to send the connection setup, so clients sending nothing do not
+// hold a connection slot forever.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
 
-    let (client_r, client_w) = stream.split();
+async fn handle_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    config: &'static X11Config,
+    cookie: Option<Arc<FakeCookie>>,
+    peer: String,
+    mut stream: S,
+) -> std::io::Result<()> {
+    let start = Instant::now();
+    let mut request =
+        match tokio::time::timeout(SETUP_TIMEOUT, SetupRequest::read(&mut stream)).await {
+            Ok(request) => request?,
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("X11 client {} sent no connection setup", peer),
+                ))
+            }
+        };
+    info!("X11 client {} connected: {}", peer, request);
+
+    // Check the fake cookie, and strip it so wsldhost can supply the real one.
+    if let Some(cookie) = cookie {
+        if !cookie.matches(&request) {
+            request
+                .refuse(&mut stream, "Invalid MIT-MAGIC-COOKIE-1 key")
+                .await?;
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("X11 client {} has invalid authorization", peer),
+            ));
+        }
+        request.auth_name.clear();
+        request.auth_data.clear();
+    }
+    let setup = request.encode();
+
+    // Use `x11\0` for the first X server, so older versions of wsldhost still work.
+    let mut server = match config.host_display {
+        0 => host::request(b"x11\0").await?,
+        index => {
+            let mut server = host::request(b"x11d").await?;
+            server.write_u8(index).await?;
+            server
+        }
+    };
+    server.write_all(&setup).await?;
+
+    let sent = AtomicU64::new(setup.len() as u64);
+    let received = AtomicU64::new(0);
+    let (client_r, client_w) = tokio::io::split(stream);
     let (server_r, server_w) = server.split();
-    let a = connect_stream(client_r, server_w);
-    let b = connect_stream(server_r, client_w);
-    either(a, b).await
+    let a = connect_stream(client_r, server_w, |size| {
+        sent.fetch_add(size as u64, Ordering::Relaxed);
+    });
+    let b = connect_stream(server_r, client_w, |size| {
+        received.fetch_add(size as u64, Ordering::Relaxed);
+    });
+    let result = either(a, b).await;
+
+    info!(
+        "X11 client {} disconnected after {:.1?}, sent {} bytes, received {} bytes",
+        peer,
+        start.elapsed(),
+        sent.load(Ordering::Relaxed),
+        received.load(Ordering::Relaxed)
+    );
+    result
 }
 
-pub async fn x11_forward(config: &'static X11Config) -> std::io::Result<()> {
-    let lock = X11Lock::acquire(config.display, config.force)?;
-    let listener = lock.bind()?;
+async fn serve_unix(
+    config: &'static X11Config,
+    listener: UnixListener,
+    limiter: Arc<Limiter>,
+    cookie: Option<Arc<FakeCookie>>,
+) -> std::io::Result<()> {
+    loop {
+        let ((stream, _), permit) = limiter.accept(|| listener.accept()).await?;
+        if !peercred::check(&config.peers, &stream) {
+            continue;
+        }
+        let peer = peercred::describe(&stream);
+
+        let cookie = cookie.clone();
+        tokio::task::spawn(async move {
+            let _permit = permit;
+            if let Err(err) = handle_stream(config, cookie, peer, stream).await {
+                eprintln!("Failed to transfer: {}", err);
+            }
+        });
+    }
+}
 
+// Peer credentials are not available for TCP, so these connections are only checked by cookie.
+async fn serve_tcp(
+    config: &'static X11Config,
+    listener: TcpListener,
+    limiter: Arc<Limiter>,
+    cookie: Option<Arc<FakeCookie>>,
+) -> std::io::Result<()> {
     loop {
-        let stream = listener.accept().await?.0;
+        let ((stream, addr), permit) = limiter.accept(|| listener.accept()).await?;
+        stream.set_nodelay(true)?;
+        let peer = addr.to_string();
 
+        let cookie = cookie.clone();
         tokio::task::spawn(async move {
-            if let Err(err) = handle_stream(stream).await {
+            let _permit = permit;
+            if let Err(err) = handle_stream(config, cookie, peer, stream).await {
                 eprintln!("Failed to transfer: {}", err);
             }
         });
     }
 }
+
+/// Forward the display of `config`. The display of the first X11 section is published to
+/// the display file of the user wsld runs for.
+pub async fn x11_forward(config: &'static X11Config, publish: bool) -> std::io::Result<()> {
+    let lock = match config.display {
+        X11Display::Auto => X11Lock::acquire_auto()?,
+        X11Display::Number(display) => X11Lock::acquire(display, config.force)?,
+    };
+    let display = lock.display();
+
+    let mut unix_listeners = Vec::new();
+    if config.listen_unix {
+        unix_listeners.push(lock.bind()?);
+    }
+    if config.listen_abstract {
+        unix_listeners.push(lock.bind_abstract()?);
+    }
+    let tcp_listener = match config.listen_tcp {
+        true => Some(lock.bind_tcp().await?),
+        false => None,
+    };
+    if unix_listeners.is_empty() && tcp_listener.is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("no listener enabled for X{}", display),
+        ));
+    }
+
+    let cookie = match config.auth {
+        true => Some(Arc::new(FakeCookie::install(display)?)),
+        false => None,
+    };
+    let limiter = Arc::new(Limiter::new(&config.limits));
+
+    let _published = match publish {
+        true => match PublishedDisplay::publish(display, peercred::target_uid()) {
+            Ok(published) => Some(published),
+            Err(err) => {
+                warn!("cannot publish display :{}: {}", display, err);
+                None
+            }
+        },
+        false => None,
+    };
+
+    let mut tasks = Vec::new();
+    for listener in unix_listeners {
+        tasks.push(tokio::task::spawn(serve_unix(
+            config,
+            listener,
+            limiter.clone(),
+            cookie.clone(),
+        )));
+    }
+    if let Some(listener) = tcp_listener {
+        tasks.push(tokio::task::spawn(serve_tcp(
+            config,
+            listener,
+            limiter.clone(),
+            cookie.clone(),
+        )));
+    }
+
+    // Keep the lock, the cookie and the published display until all listeners are done.
+    for task in tasks {
+        if let Err(err) = task.await.unwrap() {
+            eprintln!("X{} listener failed: {}", display, err);
+        }
+    }
+    Ok(())
+}
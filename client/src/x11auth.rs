@@ -0,0 +1,199 @@
+use super::peercred;
+
+use log::{info, warn};
+use std::ffi::{CStr, OsStr};
+use std::io::{Error, ErrorKind, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const MIT_MAGIC_COOKIE: &[u8] = b"MIT-MAGIC-COOKIE-1";
+const COOKIE_LEN: usize = 16;
+
+fn pad(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+/// The connection setup request sent by X11 clients.
+pub struct SetupRequest {
+    byte_order: u8,
+    major: u16,
+    minor: u16,
+    pub auth_name: Vec<u8>,
+    pub auth_data: Vec<u8>,
+}
+
+impl SetupRequest {
+    fn u16_from(&self, bytes: [u8; 2]) -> u16 {
+        if self.byte_order == b'B' {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+
+    fn u16_to(&self, value: u16) -> [u8; 2] {
+        if self.byte_order == b'B' {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(r: &mut R) -> std::io::Result<Self> {
+        let mut header = [0; 12];
+        r.read_exact(&mut header).await?;
+        if header[0] != b'B' && header[0] != b'l' {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid X11 byte order"));
+        }
+
+        let mut request = SetupRequest {
+            byte_order: header[0],
+            major: 0,
+            minor: 0,
+            auth_name: Vec::new(),
+            auth_data: Vec::new(),
+        };
+        request.major = request.u16_from([header[2], header[3]]);
+        request.minor = request.u16_from([header[4], header[5]]);
+        let name_len = request.u16_from([header[6], header[7]]) as usize;
+        let data_len = request.u16_from([header[8], header[9]]) as usize;
+
+        let mut buf = vec![0; name_len + pad(name_len) + data_len + pad(data_len)];
+        r.read_exact(&mut buf).await?;
+        let data_start = name_len + pad(name_len);
+        request.auth_name = buf[..name_len].to_vec();
+        request.auth_data = buf[data_start..data_start + data_len].to_vec();
+        Ok(request)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let name_len = self.auth_name.len();
+        let data_len = self.auth_data.len();
+        let mut buf = Vec::with_capacity(12 + name_len + data_len + 6);
+        buf.extend_from_slice(&[self.byte_order, 0]);
+        buf.extend_from_slice(&self.u16_to(self.major));
+        buf.extend_from_slice(&self.u16_to(self.minor));
+        buf.extend_from_slice(&self.u16_to(name_len as u16));
+        buf.extend_from_slice(&self.u16_to(data_len as u16));
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&self.auth_name);
+        buf.resize(buf.len() + pad(name_len), 0);
+        buf.extend_from_slice(&self.auth_data);
+        buf.resize(buf.len() + pad(data_len), 0);
+        buf
+    }
+
+    /// Reply to the client that the connection is refused for `reason`.
+    pub async fn refuse<W: AsyncWrite + Unpin>(
+        &self,
+        w: &mut W,
+        reason: &str,
+    ) -> std::io::Result<()> {
+        let reason = reason.as_bytes();
+        let mut buf = Vec::with_capacity(8 + reason.len() + 3);
+        buf.extend_from_slice(&[0, reason.len() as u8]);
+        buf.extend_from_slice(&self.u16_to(self.major));
+        buf.extend_from_slice(&self.u16_to(self.minor));
+        buf.extend_from_slice(&self.u16_to(((reason.len() + pad(reason.len())) / 4) as u16));
+        buf.extend_from_slice(reason);
+        buf.resize(buf.len() + pad(reason.len()), 0);
+        w.write_all(&buf).await
+    }
+}
+
+impl std::fmt::Display for SetupRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let byte_order = match self.byte_order {
+            b'B' => "big-endian",
+            _ => "little-endian",
+        };
+        let auth_name = match self.auth_name.is_empty() {
+            true => "no authorization".into(),
+            false => String::from_utf8_lossy(&self.auth_name),
+        };
+        write!(
+            f,
+            "X{}.{} {}, {}",
+            self.major, self.minor, byte_order, auth_name
+        )
+    }
+}
+
+// Home directory and primary group of `uid`.
+fn passwd(uid: u32) -> std::io::Result<(PathBuf, u32)> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    let err = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if result.is_null() {
+        return Err(match err {
+            0 => Error::new(ErrorKind::NotFound, format!("no user with uid {}", uid)),
+            err => Error::from_raw_os_error(err),
+        });
+    }
+    let home = unsafe { CStr::from_ptr(pwd.pw_dir) };
+    Ok((
+        PathBuf::from(OsStr::from_bytes(home.to_bytes())),
+        pwd.pw_gid,
+    ))
+}
+
+// Run xauth as the user wsld runs for, so that the cookie goes into their Xauthority file
+// rather than root's when wsld runs as root.
+fn xauth(args: &[&str]) -> std::io::Result<()> {
+    let mut command = Command::new("xauth");
+    let uid = peercred::target_uid();
+    if unsafe { libc::geteuid() } != uid {
+        let (home, gid) = passwd(uid)?;
+        command
+            .uid(uid)
+            .gid(gid)
+            .env("XAUTHORITY", home.join(".Xauthority"))
+            .env("HOME", home);
+    }
+    let status = command.args(args).status()?;
+    if !status.success() {
+        return Err(Error::other(format!("xauth {} failed", args[0])));
+    }
+    Ok(())
+}
+
+/// A fake MIT-MAGIC-COOKIE-1 for a display, added to the Xauthority file and removed when
+/// dropped. Clients are checked against it, and the cookie never leaves WSL.
+pub struct FakeCookie {
+    display: u32,
+    pub cookie: [u8; COOKIE_LEN],
+}
+
+impl FakeCookie {
+    pub fn install(display: u32) -> std::io::Result<Self> {
+        let mut cookie = [0; COOKIE_LEN];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut cookie)?;
+
+        let hex: String = cookie.iter().map(|byte| format!("{:02x}", byte)).collect();
+        xauth(&[
+            "add",
+            &format!(":{}", display),
+            std::str::from_utf8(MIT_MAGIC_COOKIE).unwrap(),
+            &hex,
+        ])?;
+        info!("added X11 authorization for display :{}", display);
+        Ok(FakeCookie { display, cookie })
+    }
+
+    pub fn matches(&self, request: &SetupRequest) -> bool {
+        request.auth_name == MIT_MAGIC_COOKIE && bool::from(request.auth_data.ct_eq(&self.cookie))
+    }
+}
+
+impl Drop for FakeCookie {
+    fn drop(&mut self) {
+        if let Err(err) = xauth(&["remove", &format!(":{}", self.display)]) {
+            warn!("cannot remove X11 authorization: {}", err);
+        }
+    }
+}
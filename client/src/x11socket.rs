@@ -1,7 +1,45 @@
-use std::fs::{self, Permissions};
-use std::io::{Error, Result, Write};
-use std::os::unix::fs::PermissionsExt;
-use tokio::net::UnixListener;
+use std::ffi::CStr;
+use std::fs::{self, File, Permissions};
+use std::io::{Error, Read, Result, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::SocketAddr as UnixSocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::net::{TcpListener, UnixListener};
+
+// X servers listening on TCP use port 6000 + display number.
+const X_TCP_PORT: u16 = 6000;
+
+// Displays scanned by `X11Lock::acquire_auto`.
+const MAX_AUTO_DISPLAY: u32 = 64;
+
+// Name of the file holding the display chosen by wsld, in `display_dir`.
+const DISPLAY_FILE: &str = "display";
+const DISPLAY_FILE_C: &CStr = c"display";
+
+/// Directory of the file holding the display chosen by wsld for `uid`, for shell profiles and
+/// `wsld env`. It is private to `uid`, so other users cannot plant or change the file.
+pub fn display_dir(uid: u32) -> PathBuf {
+    PathBuf::from(format!("/tmp/.wsld-{}", uid))
+}
+
+/// Path of the file holding the display chosen by wsld for `uid`.
+pub fn display_file(uid: u32) -> PathBuf {
+    display_dir(uid).join(DISPLAY_FILE)
+}
+
+// Whether an X server not holding a lock file is serving the display, e.g. WSLg.
+fn socket_in_use(display: u32) -> bool {
+    let name = format!("/tmp/.X11-unix/X{}", display);
+    if std::os::unix::net::UnixStream::connect(&name).is_ok() {
+        return true;
+    }
+    UnixSocketAddr::from_abstract_name(&name)
+        .and_then(|addr| std::os::unix::net::UnixStream::connect_addr(&addr))
+        .is_ok()
+}
 
 pub struct X11Lock {
     display: u32,
@@ -27,7 +65,7 @@ impl X11Lock {
             match file {
                 Ok(mut file) => {
                     // Fresh file, just write our PID into it and we got the lock
-                    match write!(file, "{:>10}\n", std::process::id()) {
+                    match writeln!(file, "{:>10}", std::process::id()) {
                         Ok(_) => return Ok(X11Lock { display }),
                         Err(err) => {
                             let _ = fs::remove_file(&name);
@@ -59,6 +97,29 @@ impl X11Lock {
         }
     }
 
+    /// Acquire the first display that is neither locked by a live process nor served by
+    /// another X server.
+    pub fn acquire_auto() -> Result<Self> {
+        for display in 0..MAX_AUTO_DISPLAY {
+            if socket_in_use(display) {
+                continue;
+            }
+            match Self::acquire(display, false) {
+                Ok(lock) => return Ok(lock),
+                Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Error::new(
+            std::io::ErrorKind::AddrInUse,
+            "no free X display found",
+        ))
+    }
+
+    pub fn display(&self) -> u32 {
+        self.display
+    }
+
     pub fn bind(&self) -> Result<UnixListener> {
         let name = format!("/tmp/.X11-unix/X{}", self.display);
 
@@ -70,4 +131,137 @@ impl X11Lock {
         let _ = std::fs::set_permissions(&name, Permissions::from_mode(0o777));
         socket
     }
+
+    /// Bind `@/tmp/.X11-unix/X<n>` in the abstract namespace, which does not depend on the
+    /// directory being writable.
+    pub fn bind_abstract(&self) -> Result<UnixListener> {
+        let name = format!("/tmp/.X11-unix/X{}", self.display);
+        let addr = UnixSocketAddr::from_abstract_name(name)?;
+        let socket = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+        socket.set_nonblocking(true)?;
+        UnixListener::from_std(socket)
+    }
+
+    /// Bind `127.0.0.1:6000+n`, for clients using `DISPLAY=localhost:<n>`.
+    pub async fn bind_tcp(&self) -> Result<TcpListener> {
+        let port = u16::try_from(self.display)
+            .ok()
+            .and_then(|display| X_TCP_PORT.checked_add(display))
+            .ok_or_else(|| {
+                Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("X{} has no TCP port", self.display),
+                )
+            })?;
+        TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port))).await
+    }
+}
+
+/// Writes the display to the display file of `uid`, and removes it when dropped unless another
+/// wsld has replaced it since.
+pub struct PublishedDisplay {
+    dir: File,
+    content: String,
+}
+
+impl PublishedDisplay {
+    pub fn publish(display: u32, uid: u32) -> Result<Self> {
+        let dir = open_private_dir(&display_dir(uid), uid)?;
+        let content = format!(":{}\n", display);
+
+        let fd = unsafe {
+            libc::openat(
+                dir.as_raw_fd(),
+                DISPLAY_FILE_C.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                0o644,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(content.as_bytes())?;
+        Ok(PublishedDisplay { dir, content })
+    }
+}
+
+impl Drop for PublishedDisplay {
+    fn drop(&mut self) {
+        let fd = unsafe {
+            libc::openat(
+                self.dir.as_raw_fd(),
+                DISPLAY_FILE_C.as_ptr(),
+                libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return;
+        }
+        let mut content = String::new();
+        let _ = unsafe { File::from_raw_fd(fd) }.read_to_string(&mut content);
+        if content == self.content {
+            unsafe { libc::unlinkat(self.dir.as_raw_fd(), DISPLAY_FILE_C.as_ptr(), 0) };
+        }
+    }
+}
+
+/// Create `path` as a directory only accessible by `uid`, or check that it already is one, and
+/// open it without following symlinks.
+fn open_private_dir(path: &Path, uid: u32) -> Result<File> {
+    match fs::DirBuilder::new().mode(0o700).create(path) {
+        Ok(()) => {
+            if unsafe { libc::geteuid() } != uid {
+                std::os::unix::fs::chown(path, Some(uid), None)?;
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(err) => return Err(err),
+    }
+
+    let dir = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(path)?;
+    let metadata = dir.metadata()?;
+    if metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is not a directory private to uid {}",
+                path.display(),
+                uid
+            ),
+        ));
+    }
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_dir_is_checked() {
+        let uid = unsafe { libc::geteuid() };
+        let base = std::env::temp_dir().join(format!("wsld-x11socket-test-{}", std::process::id()));
+        fs::create_dir_all(&base).unwrap();
+
+        let private = base.join("private");
+        open_private_dir(&private, uid).unwrap();
+        open_private_dir(&private, uid).unwrap();
+        let mode = fs::metadata(&private).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        let shared = base.join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, Permissions::from_mode(0o755)).unwrap();
+        assert!(open_private_dir(&shared, uid).is_err());
+
+        let link = base.join("link");
+        std::os::unix::fs::symlink(&private, &link).unwrap();
+        assert!(open_private_dir(&link, uid).is_err());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
 }
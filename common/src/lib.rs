@@ -0,0 +1,106 @@
+//! Code shared by wsld and wsldhost.
+
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{Error, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Deserialize either a single value or an array of values into a `Vec`, e.g. either an `[x11]`
+/// table or `[[x11]]` tables. The form is told from the type of the value, so errors inside the
+/// value are reported as they are.
+pub fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    struct OneOrMany<T>(PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de>> OneOrMany<T> {
+        fn one<E: Error, V: IntoDeserializer<'de, E>>(value: V) -> Result<Vec<T>, E> {
+            T::deserialize(value.into_deserializer()).map(|value| vec![value])
+        }
+    }
+
+    impl<'de, T: Deserialize<'de>> Visitor<'de> for OneOrMany<T> {
+        type Value = Vec<T>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a value or an array of values")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Vec<T>, A::Error> {
+            Vec::deserialize(SeqAccessDeserializer::new(seq))
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Vec<T>, A::Error> {
+            T::deserialize(MapAccessDeserializer::new(map)).map(|value| vec![value])
+        }
+
+        fn visit_str<E: Error>(self, value: &str) -> Result<Vec<T>, E> {
+            Self::one(value)
+        }
+
+        fn visit_i64<E: Error>(self, value: i64) -> Result<Vec<T>, E> {
+            Self::one(value)
+        }
+
+        fn visit_u64<E: Error>(self, value: u64) -> Result<Vec<T>, E> {
+            Self::one(value)
+        }
+
+        fn visit_f64<E: Error>(self, value: f64) -> Result<Vec<T>, E> {
+            Self::one(value)
+        }
+
+        fn visit_bool<E: Error>(self, value: bool) -> Result<Vec<T>, E> {
+            Self::one(value)
+        }
+    }
+
+    deserializer.deserialize_any(OneOrMany(PhantomData))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(deny_unknown_fields)]
+    struct Section {
+        display: u32,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Config {
+        #[serde(default, deserialize_with = "one_or_many")]
+        x11: Vec<Section>,
+        #[serde(default, deserialize_with = "one_or_many")]
+        names: Vec<String>,
+    }
+
+    #[test]
+    fn single_and_array() {
+        let config: Config = toml::from_str("names = \"a\"\n[x11]\ndisplay = 1").unwrap();
+        assert_eq!(config.x11, [Section { display: 1 }]);
+        assert_eq!(config.names, ["a"]);
+
+        let config: Config =
+            toml::from_str("names = [\"a\", \"b\"]\n[[x11]]\ndisplay = 1\n[[x11]]\ndisplay = 2")
+                .unwrap();
+        assert_eq!(config.x11, [Section { display: 1 }, Section { display: 2 }]);
+        assert_eq!(config.names, ["a", "b"]);
+
+        let config: Config = toml::from_str("").unwrap();
+        assert!(config.x11.is_empty());
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        let err = toml::from_str::<Config>("[x11]\ndispaly = 1").unwrap_err();
+        assert!(err.to_string().contains("dispaly"), "{}", err);
+
+        let err = toml::from_str::<Config>("[[x11]]\ndisplay = \"1\"").unwrap_err();
+        assert!(err.to_string().contains("invalid type"), "{}", err);
+    }
+}
@@ -0,0 +1,81 @@
+use super::CONFIG;
+
+use hmac::{Hmac, Mac};
+use log::error;
+use once_cell::sync::Lazy;
+use sha2::Sha256;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const CHALLENGE_LEN: usize = 32;
+
+// How long to wait for the guest to answer the challenge.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Replies to `auth` requests, followed by the challenge if one is sent.
+const AUTH_NOT_REQUIRED: u8 = 0;
+const AUTH_CHALLENGE: u8 = 1;
+
+// Result of checking the response to the challenge.
+const AUTH_SUCCESS: u8 = 0;
+const AUTH_FAILURE: u8 = 1;
+
+static AUTH_KEY: Lazy<Option<Vec<u8>>> = Lazy::new(|| {
+    let path = CONFIG.auth_key_file.as_ref()?;
+    match std::fs::read_to_string(path) {
+        Ok(key) => Some(key.trim().as_bytes().to_vec()),
+        Err(err) => {
+            error!("Cannot read {:?}: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+});
+
+/// Load the key, so a missing key file is reported at start up.
+pub fn init() {
+    Lazy::force(&AUTH_KEY);
+}
+
+/// Whether guests must authenticate before requesting functions.
+pub fn required() -> bool {
+    AUTH_KEY.is_some()
+}
+
+/// Handle an `auth` request. Without a key, reply that authentication is not required.
+/// Otherwise challenge the guest to prove that it knows the key: the response is an HMAC-SHA256
+/// of a random challenge keyed with the shared key. Returns whether the guest authenticated.
+pub async fn handle_auth(stream: &mut TcpStream) -> std::io::Result<bool> {
+    let key = match &*AUTH_KEY {
+        Some(key) => key,
+        None => {
+            stream.write_u8(AUTH_NOT_REQUIRED).await?;
+            return Ok(false);
+        }
+    };
+
+    let mut challenge = [0; CHALLENGE_LEN];
+    getrandom::getrandom(&mut challenge).map_err(std::io::Error::from)?;
+    let mut buf = Vec::with_capacity(1 + CHALLENGE_LEN);
+    buf.push(AUTH_CHALLENGE);
+    buf.extend_from_slice(&challenge);
+    stream.write_all(&buf).await?;
+
+    let mut response = [0; 32];
+    tokio::time::timeout(AUTH_TIMEOUT, stream.read_exact(&mut response))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "guest did not answer the challenge"))??;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(&challenge);
+    if mac.verify_slice(&response).is_err() {
+        stream.write_u8(AUTH_FAILURE).await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "guest failed to authenticate",
+        ));
+    }
+    stream.write_u8(AUTH_SUCCESS).await?;
+    Ok(true)
+}
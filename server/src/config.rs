@@ -1,30 +1,481 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
+use wsld_common::one_or_many;
+
+const CONFIG_FILE_NAME: &str = "wsldhost.toml";
+
+const DEFAULT_SERVICE_PORT: u32 = 6000;
+const DEFAULT_DISPLAY: &str = "127.0.0.1:6000";
+const DEFAULT_SSH_AGENT_PIPE: &str = "openssh-ssh-agent";
+const DEFAULT_LOG_LEVEL: &str = "info";
+// Same as the keepalive defaults of wsld.
+const DEFAULT_TCP_KEEPALIVE_INTERVAL: u64 = 10;
+const DEFAULT_TCP_KEEPALIVE_RETRIES: u32 = 3;
 
 fn parse_uuid(str: &str) -> std::io::Result<Uuid> {
     str.parse()
         .map_err(|err| Error::new(ErrorKind::InvalidInput, format!("Invalid UUID: {}", err)))
 }
 
-#[derive(Debug, Parser)]
+fn parse_hex(str: &str) -> Option<Vec<u8>> {
+    if !str.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..str.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(str.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Configuration of wsldhost. Each option can be given either in the config file or as a
+/// command line flag, with flags taking precedence.
+#[derive(Debug, Default, Parser, Serialize, Deserialize)]
 #[clap(name = "wsldhost")]
+#[serde(default)]
 pub struct Config {
-    #[clap(short, long)]
-    pub daemon: bool,
+    /// Read configuration from this file. Default to wsldhost.toml next to the executable
+    #[clap(long, value_name = "PATH")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// Print the effective configuration and exit
+    #[clap(long)]
+    #[serde(skip)]
+    pub print_config: bool,
+
+    /// Run in the background. `--daemon=false` overrides `daemon = true` in the config file
+    #[clap(
+        short,
+        long,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub daemon: Option<bool>,
 
-    #[clap(short = 'p', long, default_value = "6000")]
-    pub service_port: u32,
+    /// Same as `--daemon=false`
+    #[clap(long, conflicts_with = "daemon")]
+    #[serde(skip)]
+    pub no_daemon: bool,
+
+    #[clap(short = 'p', long)]
+    pub service_port: Option<u32>,
 
     #[clap(name = "VMID", value_parser = parse_uuid)]
     pub vmid: Option<Uuid>,
 
+    /// Only accept requests from guests knowing the key in this file
+    #[clap(long, value_name = "PATH")]
+    pub auth_key_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub log: LogConfig,
+
+    #[clap(flatten)]
+    pub access: AccessConfig,
+
+    #[clap(flatten)]
+    pub limits: LimitConfig,
+
     #[clap(flatten)]
     pub x11: X11Config,
+
+    #[clap(flatten)]
+    pub ssh_agent: SshAgentConfig,
+
+    #[clap(flatten)]
+    pub tcp: TcpConfig,
+
+    #[clap(flatten)]
+    pub dns: DnsConfig,
+
+    #[clap(flatten)]
+    pub pipe: PipeConfig,
+}
+
+// Recursively merge `overrides` into `base`.
+fn merge(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => merge(base, value),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn read_file(path: &Path, explicit: bool) -> Result<toml::Table, String> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound && !explicit => return Ok(Default::default()),
+        Err(err) => return Err(format!("cannot read {:?}: {}", path, err)),
+    };
+    toml::from_str(&content).map_err(|err| format!("invalid config file {:?}: {}", path, err))
+}
+
+impl Config {
+    /// Parse command line flags, and merge them with the config file.
+    pub fn load() -> Result<Config, String> {
+        let args = Config::parse();
+
+        let (path, explicit) = match &args.config {
+            Some(path) => (path.clone(), true),
+            None => {
+                let mut path = std::env::current_exe().map_err(|err| err.to_string())?;
+                path.set_file_name(CONFIG_FILE_NAME);
+                (path, false)
+            }
+        };
+
+        let mut table = read_file(&path, explicit)?;
+        let mut flags = toml::Table::try_from(&args).map_err(|err| err.to_string())?;
+        if args.no_daemon {
+            flags.insert("daemon".to_owned(), toml::Value::Boolean(false));
+        }
+        merge(&mut table, flags);
+
+        let mut config: Config = toml::Value::Table(table)
+            .try_into()
+            .map_err(|err| format!("invalid config file {:?}: {}", path, err))?;
+        config.config = Some(path);
+        config.print_config = args.print_config;
+        Ok(config)
+    }
+
+    /// Print the configuration in the config file format, with defaults filled in.
+    pub fn print(&self) -> Result<(), String> {
+        let defaults = Config {
+            service_port: Some(self.service_port()),
+            log: LogConfig {
+                level: Some(self.log.level().to_owned()),
+                file: None,
+            },
+            x11: X11Config {
+                display: vec![DEFAULT_DISPLAY.to_owned()],
+                cookie: Vec::new(),
+            },
+            ssh_agent: SshAgentConfig {
+                pipe: Some(self.ssh_agent.pipe().to_owned()),
+            },
+            ..Default::default()
+        };
+        let mut resolved = toml::Table::try_from(defaults).map_err(|err| err.to_string())?;
+        merge(
+            &mut resolved,
+            toml::Table::try_from(self).map_err(|err| err.to_string())?,
+        );
+        print!(
+            "{}",
+            toml::to_string_pretty(&resolved).map_err(|err| err.to_string())?
+        );
+        Ok(())
+    }
+
+    pub fn service_port(&self) -> u32 {
+        self.service_port.unwrap_or(DEFAULT_SERVICE_PORT)
+    }
+
+    pub fn daemon(&self) -> bool {
+        self.daemon.unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Default, Parser, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Log level, or filters in the same format as RUST_LOG. Default to info
+    #[clap(long = "log-level", value_name = "FILTER")]
+    pub level: Option<String>,
+
+    /// Append logs to this file instead of writing to the console
+    #[clap(long = "log-file", value_name = "PATH")]
+    pub file: Option<PathBuf>,
+}
+
+impl LogConfig {
+    pub fn level(&self) -> &str {
+        self.level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL)
+    }
+}
+
+/// Functions that the guest can request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Function {
+    X11,
+    Time,
+    Tcp,
+    TcpHost,
+    TcpListeners,
+    Udp,
+    SshAgent,
+    Pipe,
+    Dns,
+    Reverse,
+}
+
+/// An inclusive range of ports, written as either `1234` or `1000-2000`.
+#[derive(Debug, Clone, Copy)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
 }
 
-#[derive(Debug, Parser)]
+fn parse_port_range(str: &str) -> Result<PortRange, String> {
+    let invalid = || format!("invalid port range {:?}", str);
+    let (start, end) = str.split_once('-').unwrap_or((str, str));
+    let start = start.trim().parse().map_err(|_| invalid())?;
+    let end = end.trim().parse().map_err(|_| invalid())?;
+    if start > end {
+        return Err(invalid());
+    }
+    Ok(PortRange { start, end })
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.start == self.end {
+            serializer.serialize_u16(self.start)
+        } else {
+            serializer.serialize_str(&format!("{}-{}", self.start, self.end))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Port(u16),
+            Range(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Port(port) => Ok(PortRange {
+                start: port,
+                end: port,
+            }),
+            Repr::Range(str) => parse_port_range(&str).map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[derive(Debug, Default, Parser, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessConfig {
+    /// Only allow the guest to use this function. Can be specified multiple times. Default to all
+    #[clap(long = "enable", value_name = "FUNCTION", value_enum)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub enable: Vec<Function>,
+
+    /// Only allow connecting to this port or range of ports. Can be specified multiple times. Default to all
+    #[clap(long = "allow-port", value_name = "PORTS", value_parser = parse_port_range)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allow_port: Vec<PortRange>,
+
+    /// Only allow connecting to this host, or its subdomains if written as `*.domain`, when the
+    /// guest names a host (e.g. through the SOCKS proxy). Can be specified multiple times.
+    /// Default to all
+    #[clap(long = "allow-host", value_name = "HOST")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allow_host: Vec<String>,
+}
+
+impl AccessConfig {
+    pub fn function_enabled(&self, function: Function) -> bool {
+        self.enable.is_empty() || self.enable.contains(&function)
+    }
+
+    pub fn port_allowed(&self, port: u16) -> bool {
+        self.allow_port.is_empty() || self.allow_port.iter().any(|range| range.contains(port))
+    }
+
+    pub fn host_allowed(&self, host: &str) -> bool {
+        self.allow_host.is_empty()
+            || self
+                .allow_host
+                .iter()
+                .any(|pattern| match pattern.strip_prefix("*.") {
+                    Some(domain) => host
+                        .to_ascii_lowercase()
+                        .strip_suffix(&domain.to_ascii_lowercase())
+                        .is_some_and(|prefix| prefix.ends_with('.')),
+                    None => pattern.eq_ignore_ascii_case(host),
+                })
+    }
+}
+
+#[derive(Debug, Default, Parser, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitConfig {
+    /// Maximum number of connections from WSL open at the same time
+    #[clap(long, value_name = "N")]
+    pub max_connections: Option<usize>,
+
+    /// Maximum number of connections from WSL accepted per second
+    #[clap(long, value_name = "N")]
+    pub max_accept_rate: Option<u32>,
+}
+
+#[derive(Debug, Default, Parser, Serialize, Deserialize)]
+#[serde(default)]
 pub struct X11Config {
-    #[clap(long, default_value = "127.0.0.1:6000")]
-    pub display: String,
+    /// Address of the X server. Can be specified multiple times for multiple X servers, which
+    /// are selected by `host_display` in the WSL config. Default to 127.0.0.1:6000
+    #[clap(long)]
+    #[serde(
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub display: Vec<String>,
+
+    /// MIT-MAGIC-COOKIE-1 of the X server, in hex, sent in place of the cookie of X11 clients.
+    /// Can be specified once for all X servers, or once per X server
+    #[clap(long = "x11-cookie", value_name = "HEX")]
+    #[serde(
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub cookie: Vec<String>,
+}
+
+impl X11Config {
+    /// Get the address of the X server with `index`.
+    pub fn display(&self, index: u8) -> Option<&str> {
+        if self.display.is_empty() && index == 0 {
+            return Some(DEFAULT_DISPLAY);
+        }
+        self.display.get(index as usize).map(String::as_str)
+    }
+
+    /// Get the authorization cookie of the X server with `index`, if one is configured.
+    pub fn cookie(&self, index: u8) -> std::io::Result<Option<Vec<u8>>> {
+        let cookie = match self.cookie.as_slice() {
+            [] => return Ok(None),
+            [cookie] => cookie,
+            cookies => match cookies.get(index as usize) {
+                Some(cookie) => cookie,
+                None => return Ok(None),
+            },
+        };
+        parse_hex(cookie).map(Some).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid X11 cookie for X server {}", index),
+            )
+        })
+    }
+}
+
+#[derive(Debug, Default, Parser, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SshAgentConfig {
+    /// Name of the pipe of the SSH agent, without the \\.\pipe\ prefix. Default to openssh-ssh-agent
+    #[clap(long = "ssh-agent-pipe", value_name = "NAME")]
+    pub pipe: Option<String>,
+}
+
+impl SshAgentConfig {
+    pub fn pipe(&self) -> &str {
+        self.pipe.as_deref().unwrap_or(DEFAULT_SSH_AGENT_PIPE)
+    }
+}
+
+#[derive(Debug, Default, Parser, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TcpConfig {
+    /// Close forwarded TCP connections without any traffic for this many seconds
+    #[clap(long = "tcp-idle-timeout", value_name = "SECS")]
+    pub idle_timeout: Option<u64>,
+
+    /// Enable TCP keepalive on connections to Windows services, probing after this many idle seconds
+    #[clap(long = "tcp-keepalive", value_name = "SECS")]
+    pub keepalive: Option<u64>,
+
+    /// Seconds between keepalive probes. Default to 10
+    #[clap(long = "tcp-keepalive-interval", value_name = "SECS")]
+    pub keepalive_interval: Option<u64>,
+
+    /// Number of unanswered keepalive probes before dropping the connection. Default to 3
+    #[clap(long = "tcp-keepalive-retries", value_name = "N")]
+    pub keepalive_retries: Option<u32>,
+}
+
+impl TcpConfig {
+    pub fn keepalive_interval(&self) -> u64 {
+        self.keepalive_interval
+            .unwrap_or(DEFAULT_TCP_KEEPALIVE_INTERVAL)
+    }
+
+    pub fn keepalive_retries(&self) -> u32 {
+        self.keepalive_retries
+            .unwrap_or(DEFAULT_TCP_KEEPALIVE_RETRIES)
+    }
+}
+
+#[derive(Debug, Default, Parser, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DnsConfig {
+    /// Relay DNS queries to this server instead of resolving with the host resolver
+    #[clap(long = "dns-upstream")]
+    pub upstream: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default, Parser, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PipeConfig {
+    /// Allow forwarding of the named pipe \\.\pipe\<NAME>. Can be specified multiple times
+    #[clap(long = "allow-pipe", value_name = "NAME")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn daemon_flag(args: &[&str]) -> (Option<bool>, bool) {
+        let args = Config::try_parse_from([&["wsldhost"], args].concat()).unwrap();
+        (args.daemon, args.no_daemon)
+    }
+
+    #[test]
+    fn daemon_flags() {
+        assert_eq!(daemon_flag(&[]), (None, false));
+        assert_eq!(daemon_flag(&["--daemon"]), (Some(true), false));
+        assert_eq!(daemon_flag(&["-d"]), (Some(true), false));
+        assert_eq!(daemon_flag(&["--daemon=false"]), (Some(false), false));
+        assert_eq!(daemon_flag(&["--no-daemon"]), (None, true));
+        assert!(Config::try_parse_from(["wsldhost", "--daemon", "--no-daemon"]).is_err());
+
+        // The VMID is not taken as the value of `--daemon`.
+        let vmid = "12345678-1234-1234-1234-123456789abc";
+        let args = Config::try_parse_from(["wsldhost", "--daemon", vmid]).unwrap();
+        assert_eq!(
+            (args.daemon, args.vmid),
+            (Some(true), Some(vmid.parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn flags_override_daemon_in_file() {
+        let mut table: toml::Table = toml::from_str("daemon = true").unwrap();
+        let args = Config::try_parse_from(["wsldhost", "--daemon=false"]).unwrap();
+        merge(&mut table, toml::Table::try_from(&args).unwrap());
+        let config: Config = toml::Value::Table(table).try_into().unwrap();
+        assert!(!config.daemon());
+    }
 }
@@ -0,0 +1,165 @@
+use super::util::{read_frame, write_frame};
+use super::CONFIG;
+
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpStream, UdpSocket};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NOERROR: u8 = 0;
+const RCODE_FORMERR: u8 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_NOTIMP: u8 = 4;
+
+// The host resolver does not tell us the TTL, so use a short one.
+const ANSWER_TTL: u32 = 60;
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+// WinSock errors returned by the resolver.
+const WSAHOST_NOT_FOUND: i32 = 11001;
+const WSANO_DATA: i32 = 11004;
+
+struct Question {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    // Offset of the end of the question section in the query.
+    end: usize,
+}
+
+fn parse_question(query: &[u8]) -> Option<Question> {
+    // Header is 12 bytes, and we only support a single question.
+    if query.len() < 12 || query[4..6] != [0, 1] {
+        return None;
+    }
+
+    let mut pos = 12;
+    let mut labels = Vec::new();
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Name compression is not expected in questions.
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        labels.push(std::str::from_utf8(query.get(pos..pos + len)?).ok()?);
+        pos += len;
+    }
+
+    let fields = query.get(pos..pos + 4)?;
+    Some(Question {
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([fields[0], fields[1]]),
+        qclass: u16::from_be_bytes([fields[2], fields[3]]),
+        end: pos + 4,
+    })
+}
+
+fn build_response(query: &[u8], question: &[u8], rcode: u8, answers: &[IpAddr]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(512);
+    buf.extend_from_slice(&query[0..2]);
+    // Set QR, keep OPCODE and RD from the query.
+    buf.push(0x80 | (query[2] & 0x79));
+    // Set RA.
+    buf.push(0x80 | rcode);
+    buf.extend_from_slice(&(!question.is_empty() as u16).to_be_bytes());
+    buf.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+    buf.extend_from_slice(question);
+
+    for addr in answers {
+        // Pointer to the name in the question.
+        buf.extend_from_slice(&[0xC0, 12]);
+        let (ty, data) = match addr {
+            IpAddr::V4(addr) => (TYPE_A, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (TYPE_AAAA, addr.octets().to_vec()),
+        };
+        buf.extend_from_slice(&ty.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&data);
+    }
+
+    buf
+}
+
+// Answer A and AAAA queries using the host resolver, so the result is the same as what
+// Windows applications see.
+async fn resolve_local(query: &[u8]) -> std::io::Result<Vec<u8>> {
+    if query.len() < 12 {
+        return Err(Error::new(ErrorKind::InvalidData, "DNS query too short"));
+    }
+
+    let question = match parse_question(query) {
+        Some(question) => question,
+        None => return Ok(build_response(query, &[], RCODE_FORMERR, &[])),
+    };
+    let question_bytes = &query[12..question.end];
+
+    if question.qclass != CLASS_IN || (question.qtype != TYPE_A && question.qtype != TYPE_AAAA) {
+        return Ok(build_response(query, question_bytes, RCODE_NOTIMP, &[]));
+    }
+
+    let (rcode, answers) = match tokio::net::lookup_host((question.name.as_str(), 0)).await {
+        Ok(addrs) => {
+            let answers: Vec<IpAddr> = addrs
+                .map(|addr| addr.ip())
+                .filter(|addr| addr.is_ipv4() == (question.qtype == TYPE_A))
+                .collect();
+            (RCODE_NOERROR, answers)
+        }
+        Err(err) => match err.raw_os_error() {
+            Some(WSAHOST_NOT_FOUND) => (RCODE_NXDOMAIN, Vec::new()),
+            Some(WSANO_DATA) => (RCODE_NOERROR, Vec::new()),
+            _ => (RCODE_SERVFAIL, Vec::new()),
+        },
+    };
+
+    Ok(build_response(query, question_bytes, rcode, &answers))
+}
+
+async fn relay(query: &[u8], upstream: SocketAddr) -> std::io::Result<Vec<u8>> {
+    let socket = if upstream.is_ipv4() {
+        UdpSocket::bind(("0.0.0.0", 0)).await?
+    } else {
+        UdpSocket::bind(("::", 0)).await?
+    };
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let size = tokio::time::timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buf))
+            .await
+            .map_err(|_| Error::from(ErrorKind::TimedOut))??;
+        // Ignore stray responses that do not match the ID.
+        if size >= 2 && buf[0..2] == query[0..2] {
+            buf.truncate(size);
+            return Ok(buf);
+        }
+    }
+}
+
+pub async fn handle_dns(mut stream: TcpStream) -> std::io::Result<()> {
+    let (mut client_r, mut client_w) = stream.split();
+
+    while let Some(query) = read_frame(&mut client_r).await? {
+        let response = match CONFIG.dns.upstream {
+            Some(upstream) => relay(&query, upstream).await?,
+            None => resolve_local(&query).await?,
+        };
+        write_frame(&mut client_w, &response).await?;
+    }
+
+    Ok(())
+}
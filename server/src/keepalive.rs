@@ -0,0 +1,40 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::io::Error;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+// Defined in ws2ipdef.h, but missing from winapi.
+const TCP_KEEPCNT: i32 = 16;
+
+/// Enable TCP keepalive on `stream`, probing after `time` without traffic and then every
+/// `interval`, and dropping the connection after `retries` unanswered probes.
+pub fn set(
+    stream: &TcpStream,
+    time: Duration,
+    interval: Duration,
+    retries: u32,
+) -> std::io::Result<()> {
+    use std::os::windows::io::AsRawSocket;
+    use winapi::shared::minwindef::DWORD;
+    use winapi::shared::ws2def::IPPROTO_TCP;
+    use winapi::um::winsock2::{setsockopt, WSAGetLastError, SOCKET_ERROR};
+
+    SockRef::from(stream)
+        .set_tcp_keepalive(&TcpKeepalive::new().with_time(time).with_interval(interval))?;
+
+    // socket2 cannot set the number of probes on Windows.
+    let retries = retries as DWORD;
+    let ret = unsafe {
+        setsockopt(
+            stream.as_raw_socket() as _,
+            IPPROTO_TCP as _,
+            TCP_KEEPCNT,
+            &retries as *const _ as *const _,
+            std::mem::size_of_val(&retries) as _,
+        )
+    };
+    if ret == SOCKET_ERROR {
+        return Err(Error::from_raw_os_error(unsafe { WSAGetLastError() }));
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use super::CONFIG;
+
+use log::warn;
+use once_cell::sync::Lazy;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// How long to pause accepting when running out of sockets or memory.
+const BACKOFF: Duration = Duration::from_millis(500);
+
+// Accept rate limits allow bursts of up to one second worth of connections.
+const BURST: Duration = Duration::from_secs(1);
+
+// WinSock errors returned when running out of resources.
+const WSAEMFILE: i32 = 10024;
+const WSAENOBUFS: i32 = 10055;
+
+static CONNECTIONS: Lazy<Option<Arc<Semaphore>>> = Lazy::new(|| {
+    CONFIG
+        .limits
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max)))
+});
+
+// Theoretical arrival time of the next connection.
+static NEXT_ACCEPT: Lazy<Mutex<Instant>> = Lazy::new(|| Mutex::new(Instant::now()));
+
+async fn wait_rate() {
+    let interval = match CONFIG.limits.max_accept_rate {
+        Some(rate) if rate > 0 => Duration::from_secs(1) / rate,
+        _ => return,
+    };
+    let start = {
+        let mut next = NEXT_ACCEPT.lock().unwrap();
+        let start = (*next).max(Instant::now());
+        *next = start + interval;
+        start
+    };
+    if let Some(deadline) = start.checked_sub(BURST) {
+        tokio::time::sleep_until(deadline.into()).await;
+    }
+}
+
+/// Wait until the limits allow another connection. The returned permit is held for as long as
+/// the connection is open.
+pub async fn acquire() -> Option<OwnedSemaphorePermit> {
+    let permit = match &*CONNECTIONS {
+        Some(semaphore) => Some(semaphore.clone().acquire_owned().await.unwrap()),
+        None => None,
+    };
+    wait_rate().await;
+    permit
+}
+
+/// If accepting failed because of running out of resources, pause for a while and return
+/// true, so the caller can try again instead of failing.
+pub async fn backoff(err: &std::io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(WSAEMFILE | WSAENOBUFS) => {
+            warn!("cannot accept connection: {}, pausing", err);
+            tokio::time::sleep(BACKOFF).await;
+            true
+        }
+        _ => false,
+    }
+}
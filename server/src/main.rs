@@ -1,40 +1,120 @@
 // Hide console window
 #![windows_subsystem = "windows"]
 
+mod auth;
 mod config;
+mod dns;
+mod keepalive;
+mod limit;
+mod netstat;
+mod pipe;
+mod proxy_protocol;
+mod reverse;
 mod ssh_agent;
 mod tcp;
 mod time;
+mod udp;
 mod util;
 mod vmcompute;
 mod vmsocket;
 mod x11;
 
-use clap::Parser;
+use log::{error, info};
 use once_cell::sync::Lazy;
 use std::io::{Error, ErrorKind};
 use tokio::io::AsyncReadExt;
 use tokio::net::TcpStream;
 use uuid::Uuid;
 
-use config::Config;
+use config::{Config, Function};
 use vmsocket::VmSocket;
 
-static CONFIG: Lazy<Config> = Lazy::new(|| Config::parse());
+static CONFIG: Lazy<Config> = Lazy::new(|| {
+    Config::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    })
+});
+
+fn init_logger() -> std::io::Result<()> {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(CONFIG.log.level());
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    if let Some(path) = &CONFIG.log.file {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    builder.init();
+    Ok(())
+}
+
+async fn read_func(stream: &mut TcpStream) -> std::io::Result<[u8; 4]> {
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
 
 async fn handle_stream(mut stream: TcpStream) -> std::io::Result<()> {
-    // Read the function code at the start of the stream for demultiplexing
-    let func = {
-        let mut buf = [0; 4];
-        stream.read_exact(&mut buf).await?;
-        buf
+    // Read the function code at the start of the stream for demultiplexing. When a key is
+    // configured, the guest must authenticate with `auth` first, then request the function.
+    let mut func = read_func(&mut stream).await?;
+    if &func == b"auth" {
+        if !auth::handle_auth(&mut stream).await? {
+            return Ok(());
+        }
+        func = read_func(&mut stream).await?;
+    } else if auth::required() {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "unauthenticated request {:?}, auth_key_file must be set for wsld",
+                func
+            ),
+        ));
+    }
+
+    let function = match &func {
+        b"x11\0" | b"x11d" => Some(Function::X11),
+        b"time" => Some(Function::Time),
+        b"tcp\0" | b"tcps" | b"tcpp" => Some(Function::Tcp),
+        b"tcph" => Some(Function::TcpHost),
+        b"tcpl" => Some(Function::TcpListeners),
+        b"udp\0" => Some(Function::Udp),
+        b"ssha" => Some(Function::SshAgent),
+        b"pipe" => Some(Function::Pipe),
+        b"dns\0" => Some(Function::Dns),
+        b"rvsl" | b"rvac" => Some(Function::Reverse),
+        _ => None,
     };
+    if let Some(function) = function {
+        if !CONFIG.access.function_enabled(function) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("function {:?} is not enabled", function),
+            ));
+        }
+    }
 
     match &func {
         b"x11\0" => x11::handle_x11(stream).await,
+        b"x11d" => x11::handle_x11_display(stream).await,
         b"time" => time::handle_time(stream).await,
         b"tcp\0" => tcp::handle_tcp(stream).await,
+        b"tcps" => tcp::handle_tcp_status(stream).await,
+        b"tcpp" => tcp::handle_tcp_proxy(stream).await,
+        b"tcph" => tcp::handle_tcp_host(stream).await,
+        b"tcpl" => tcp::handle_tcp_listeners(stream).await,
+        b"udp\0" => udp::handle_udp(stream).await,
         b"ssha" => ssh_agent::handle_ssh_agent(stream).await,
+        b"pipe" => pipe::handle_pipe(stream).await,
+        b"dns\0" => dns::handle_dns(stream).await,
+        b"rvsl" => reverse::handle_reverse_listen(stream).await,
+        b"rvac" => reverse::handle_reverse_accept(stream).await,
         b"noop" => Ok(()),
         _ => Err(Error::new(
             ErrorKind::InvalidData,
@@ -44,15 +124,23 @@ async fn handle_stream(mut stream: TcpStream) -> std::io::Result<()> {
 }
 
 async fn task(vmid: Uuid) -> std::io::Result<()> {
-    let listener = VmSocket::bind(vmid, CONFIG.service_port).await?;
+    let listener = VmSocket::bind(vmid, CONFIG.service_port()).await?;
 
     loop {
-        let stream = listener.accept().await?;
+        let permit = limit::acquire().await;
+        let stream = loop {
+            match listener.accept().await {
+                Ok(stream) => break stream,
+                Err(err) if limit::backoff(&err).await => (),
+                Err(err) => return Err(err),
+            }
+        };
 
         tokio::task::spawn(async move {
+            let _permit = permit;
             let result = handle_stream(stream).await;
             if let Err(err) = result {
-                eprintln!("Error: {}", err);
+                error!("{}", err);
             }
         });
     }
@@ -62,7 +150,28 @@ async fn task(vmid: Uuid) -> std::io::Result<()> {
 async fn main() {
     unsafe { winapi::um::wincon::AttachConsole(winapi::um::wincon::ATTACH_PARENT_PROCESS) };
 
-    if CONFIG.daemon {
+    Lazy::force(&CONFIG);
+    if CONFIG.print_config {
+        if let Err(err) = CONFIG.print() {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(err) = init_logger() {
+        eprintln!("Cannot open log file: {}", err);
+        std::process::exit(1);
+    }
+    if let Some(path) = &CONFIG.config {
+        if path.exists() {
+            info!("loaded configuration from {:?}", path);
+        }
+    }
+
+    auth::init();
+
+    if CONFIG.daemon() {
         let mut prev_vmid = None;
         let mut future: Option<tokio::task::JoinHandle<()>> = None;
         loop {
@@ -79,7 +188,7 @@ async fn main() {
                         // Three chances, to avoid a race between get_wsl_vmid and spawn.
                         for _ in 0..3 {
                             if let Err(err) = task(vmid).await {
-                                eprintln!("Failed to listen: {}", err);
+                                error!("Failed to listen: {}", err);
                             }
                             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
                         }
@@ -98,7 +207,7 @@ async fn main() {
         };
 
         if let Err(err) = task(vmid).await {
-            eprintln!("Failed to listen: {}", err);
+            error!("Failed to listen: {}", err);
             return;
         }
     }
@@ -0,0 +1,78 @@
+use std::collections::BTreeSet;
+use std::io::Error;
+use std::net::Ipv4Addr;
+
+/// Get the set of TCP ports listening on Windows that are reachable via 127.0.0.1.
+pub fn tcp_loopback_listeners() -> std::io::Result<BTreeSet<u16>> {
+    use winapi::shared::iprtrmib::TCP_TABLE_OWNER_PID_LISTENER;
+    use winapi::shared::tcpmib::MIB_TCPTABLE_OWNER_PID;
+    use winapi::shared::winerror::{ERROR_INSUFFICIENT_BUFFER, NO_ERROR};
+    use winapi::shared::ws2def::AF_INET;
+    use winapi::um::iphlpapi::GetExtendedTcpTable;
+
+    // Use u32 as element type so the buffer is suitably aligned for the table.
+    let mut buf: Vec<u32> = Vec::new();
+    let mut size = 0;
+    loop {
+        let ret = unsafe {
+            GetExtendedTcpTable(
+                buf.as_mut_ptr() as _,
+                &mut size,
+                0,
+                AF_INET as _,
+                TCP_TABLE_OWNER_PID_LISTENER,
+                0,
+            )
+        };
+        match ret {
+            NO_ERROR => break,
+            // The table might grow between calls, so keep retrying until it fits.
+            ERROR_INSUFFICIENT_BUFFER => buf.resize((size as usize + 3) / 4, 0),
+            _ => return Err(Error::from_raw_os_error(ret as i32)),
+        }
+    }
+
+    let rows = unsafe {
+        let table = &*(buf.as_ptr() as *const MIB_TCPTABLE_OWNER_PID);
+        std::slice::from_raw_parts(table.table.as_ptr(), table.dwNumEntries as usize)
+    };
+
+    Ok(loopback_ports(
+        rows.iter().map(|row| (row.dwLocalAddr, row.dwLocalPort)),
+    ))
+}
+
+// Collect the ports of listeners bound to loopback or to all addresses, from the local address
+// and port of each row, both in network byte order as in MIB_TCPROW.
+fn loopback_ports(rows: impl IntoIterator<Item = (u32, u32)>) -> BTreeSet<u16> {
+    let mut ports = BTreeSet::new();
+    for (addr, port) in rows {
+        let addr = Ipv4Addr::from(addr.to_ne_bytes());
+        if addr.is_loopback() || addr.is_unspecified() {
+            ports.insert(u16::from_be(port as u16));
+        }
+    }
+    ports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(addr: [u8; 4], port: u16) -> (u32, u32) {
+        (u32::from_ne_bytes(addr), u16::to_be(port) as u32)
+    }
+
+    #[test]
+    fn loopback_ports_of_fixed_table() {
+        let table = [
+            row([127, 0, 0, 1], 3000),
+            row([0, 0, 0, 0], 445),
+            row([192, 168, 1, 2], 5000),
+            row([127, 0, 0, 2], 8080),
+            row([0, 0, 0, 0], 3000),
+        ];
+        let ports: Vec<u16> = loopback_ports(table).into_iter().collect();
+        assert_eq!(ports, [445, 3000, 8080]);
+    }
+}
@@ -0,0 +1,74 @@
+use super::util::{connect_stream, either};
+use super::CONFIG;
+
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
+use tokio::net::TcpStream;
+
+// Status replied to `pipe` requests.
+const STATUS_SUCCESS: u8 = 0;
+const STATUS_FAILURE: u8 = 1;
+const STATUS_NOT_ALLOWED: u8 = 2;
+
+// How often, and for how long, to retry opening a pipe whose instances are all in use.
+const BUSY_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+const BUSY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Open the named pipe `\\.\pipe\<name>`.
+pub async fn open(name: &str) -> std::io::Result<NamedPipeClient> {
+    use winapi::shared::winerror::ERROR_PIPE_BUSY;
+
+    let path = format!(r"\\.\pipe\{}", name);
+    let deadline = Instant::now() + BUSY_TIMEOUT;
+    loop {
+        match ClientOptions::new().open(&path) {
+            Ok(pipe) => return Ok(pipe),
+            // All instances of the pipe are in use, wait for the server to create a new one.
+            Err(err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => {
+                if Instant::now() >= deadline {
+                    return Err(Error::new(
+                        ErrorKind::TimedOut,
+                        format!("pipe {:?} stayed busy", name),
+                    ));
+                }
+            }
+            Err(err) => return Err(err),
+        }
+        tokio::time::sleep(BUSY_RETRY_INTERVAL).await;
+    }
+}
+
+pub async fn forward(mut stream: TcpStream, pipe: NamedPipeClient) -> std::io::Result<()> {
+    let (client_r, client_w) = stream.split();
+    let (server_r, server_w) = tokio::io::split(pipe);
+    let a = connect_stream(client_r, server_w, |_| ());
+    let b = connect_stream(server_r, client_w, |_| ());
+    either(a, b).await
+}
+
+pub async fn handle_pipe(mut stream: TcpStream) -> std::io::Result<()> {
+    let len = stream.read_u8().await?;
+    let mut name = vec![0; len as usize];
+    stream.read_exact(&mut name).await?;
+    let name = String::from_utf8(name).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+    if !CONFIG.pipe.allow.contains(&name) {
+        stream.write_u8(STATUS_NOT_ALLOWED).await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("pipe {:?} is not allowed", name),
+        ));
+    }
+
+    let pipe = match open(&name).await {
+        Ok(pipe) => pipe,
+        Err(err) => {
+            stream.write_u8(STATUS_FAILURE).await?;
+            return Err(err);
+        }
+    };
+    stream.write_u8(STATUS_SUCCESS).await?;
+    forward(stream, pipe).await
+}
@@ -0,0 +1,83 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// Version 2, PROXY command.
+const V2_PROXY: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+// Addresses of both ends need to be in the same family; map IPv4 to IPv6 if they differ.
+fn unify(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (to_v6(source), to_v6(destination))
+    }
+}
+
+fn v1_header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+    .into_bytes()
+}
+
+fn v6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn v2_header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(52);
+    buf.extend_from_slice(V2_SIGNATURE);
+    buf.push(V2_PROXY);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            buf.push(V2_TCP4);
+            buf.extend_from_slice(&12u16.to_be_bytes());
+            buf.extend_from_slice(&src.octets());
+            buf.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            buf.push(V2_TCP6);
+            buf.extend_from_slice(&36u16.to_be_bytes());
+            buf.extend_from_slice(&v6_octets(src));
+            buf.extend_from_slice(&v6_octets(dst));
+        }
+    }
+    buf.extend_from_slice(&source.port().to_be_bytes());
+    buf.extend_from_slice(&destination.port().to_be_bytes());
+    buf
+}
+
+/// Build a PROXY protocol header of `version` for a connection from `source` to
+/// `destination`.
+pub fn header(
+    version: u8,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> std::io::Result<Vec<u8>> {
+    let (source, destination) = unify(source, destination);
+    match version {
+        1 => Ok(v1_header(source, destination)),
+        2 => Ok(v2_header(source, destination)),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unknown PROXY protocol version {}", version),
+        )),
+    }
+}
@@ -0,0 +1,84 @@
+use super::util::{connect_stream, either};
+
+use once_cell::sync::Lazy;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// Connections accepted but not yet claimed by the guest. IDs are random, so that other
+// processes in the VM cannot guess them and claim connections meant for another target.
+static PENDING: Lazy<Mutex<HashMap<u64, TcpStream>>> = Lazy::new(Default::default);
+
+// How long to wait for the guest to claim an accepted connection.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Store an accepted connection under a new random ID.
+fn insert_pending(client: TcpStream) -> std::io::Result<u64> {
+    let mut pending = PENDING.lock().unwrap();
+    loop {
+        let mut id = [0; 8];
+        getrandom::getrandom(&mut id).map_err(std::io::Error::from)?;
+        let id = u64::from_ne_bytes(id);
+        if let Entry::Vacant(entry) = pending.entry(id) {
+            entry.insert(client);
+            return Ok(id);
+        }
+    }
+}
+
+pub async fn handle_reverse_listen(mut stream: TcpStream) -> std::io::Result<()> {
+    let port = stream.read_u16().await?;
+
+    let listener = match TcpListener::bind(("127.0.0.1", port)).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            stream.write_u8(1).await?;
+            return Err(err);
+        }
+    };
+    stream.write_u8(0).await?;
+
+    let (mut control_r, mut control_w) = stream.split();
+
+    // Announce each accepted connection to the guest, which will claim it with a new stream.
+    let a = async {
+        loop {
+            let (client, _) = listener.accept().await?;
+            client.set_nodelay(true)?;
+
+            let id = insert_pending(client)?;
+            tokio::task::spawn(async move {
+                tokio::time::sleep(CLAIM_TIMEOUT).await;
+                PENDING.lock().unwrap().remove(&id);
+            });
+
+            control_w.write_u64(id).await?;
+        }
+    };
+
+    // Stop listening once the guest closes the control stream.
+    let b = async {
+        let mut buf = [0; 1];
+        while control_r.read(&mut buf).await? != 0 {}
+        Ok(())
+    };
+
+    either(a, b).await
+}
+
+pub async fn handle_reverse_accept(mut stream: TcpStream) -> std::io::Result<()> {
+    let id = stream.read_u64().await?;
+    let mut client = match PENDING.lock().unwrap().remove(&id) {
+        Some(client) => client,
+        None => return Ok(()),
+    };
+
+    let (client_r, client_w) = client.split();
+    let (server_r, server_w) = stream.split();
+    let a = connect_stream(client_r, server_w, |_| ());
+    let b = connect_stream(server_r, client_w, |_| ());
+    either(a, b).await
+}
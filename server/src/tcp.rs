@@ -1,16 +1,282 @@
-use super::util::{connect_stream, either};
+use super::keepalive;
+use super::netstat;
+use super::proxy_protocol;
+use super::util::{connect_stream, Activity};
+use super::CONFIG;
 
-use tokio::io::AsyncReadExt;
+use log::debug;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 
-pub async fn handle_tcp(mut stream: TcpStream) -> std::io::Result<()> {
-    let port = stream.read_u16().await?;
-    let (client_r, client_w) = stream.split();
+// How long the remaining direction of a half-closed connection can be idle.
+const HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Status replied to connection requests. These are the same as SOCKS5 reply codes.
+const STATUS_SUCCESS: u8 = 0;
+const STATUS_FAILURE: u8 = 1;
+pub const STATUS_NOT_ALLOWED: u8 = 2;
+const STATUS_NETWORK_UNREACHABLE: u8 = 3;
+const STATUS_HOST_UNREACHABLE: u8 = 4;
+const STATUS_CONNECTION_REFUSED: u8 = 5;
+const STATUS_TIMED_OUT: u8 = 6;
+
+fn error_status(err: &Error) -> u8 {
+    match err.kind() {
+        ErrorKind::NetworkUnreachable => STATUS_NETWORK_UNREACHABLE,
+        ErrorKind::HostUnreachable => STATUS_HOST_UNREACHABLE,
+        ErrorKind::ConnectionRefused => STATUS_CONNECTION_REFUSED,
+        ErrorKind::TimedOut => STATUS_TIMED_OUT,
+        _ => STATUS_FAILURE,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum CloseReason {
+    Completed,
+    Error,
+    Idle,
+}
+
+// Number of forwarded connections closed for each reason.
+static CLOSED: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+fn record_close(reason: CloseReason) {
+    CLOSED[reason as usize].fetch_add(1, Ordering::Relaxed);
+    let count = |reason: CloseReason| CLOSED[reason as usize].load(Ordering::Relaxed);
+    debug!(
+        "connections closed: {} completed, {} errors, {} idle",
+        count(CloseReason::Completed),
+        count(CloseReason::Error),
+        count(CloseReason::Idle),
+    );
+}
+
+/// Run both directions of a connection. Once one direction ends, the other is given until it
+/// is idle for `HALF_CLOSE_TIMEOUT` to finish, so half-closed connections (e.g. of
+/// `wsld connect`) still receive responses, but a peer that vanishes cannot hold the
+/// connection open forever.
+async fn half_close<A, B>(a: A, b: B, activity: &Activity) -> std::io::Result<()>
+where
+    A: Future<Output = std::io::Result<()>>,
+    B: Future<Output = std::io::Result<()>>,
+{
+    tokio::pin!(a, b);
+    let a_done = tokio::select! {
+        result = &mut a => result.map(|_| true)?,
+        result = &mut b => result.map(|_| false)?,
+    };
+    tokio::select! {
+        result = &mut a, if !a_done => result,
+        result = &mut b, if a_done => result,
+        _ = activity.idle(HALF_CLOSE_TIMEOUT) => Ok(()),
+    }
+}
 
-    let mut server = TcpStream::connect(("127.0.0.1", port)).await?;
+async fn forward(mut stream: TcpStream, mut server: TcpStream) -> std::io::Result<()> {
     server.set_nodelay(true)?;
+    if let Some(secs) = CONFIG.tcp.keepalive {
+        keepalive::set(
+            &server,
+            Duration::from_secs(secs),
+            Duration::from_secs(CONFIG.tcp.keepalive_interval()),
+            CONFIG.tcp.keepalive_retries(),
+        )?;
+    }
+
+    let activity = Activity::new();
+    let (client_r, client_w) = stream.split();
     let (server_r, server_w) = server.split();
-    let a = connect_stream(client_r, server_w);
-    let b = connect_stream(server_r, client_w);
-    either(a, b).await
+    let a = connect_stream(client_r, server_w, |_| activity.touch());
+    let b = connect_stream(server_r, client_w, |_| activity.touch());
+    let idle = async {
+        match CONFIG.tcp.idle_timeout {
+            Some(secs) => activity.idle(Duration::from_secs(secs)).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        result = half_close(a, b, &activity) => {
+            record_close(if result.is_ok() { CloseReason::Completed } else { CloseReason::Error });
+            result
+        }
+        _ = idle => {
+            record_close(CloseReason::Idle);
+            Ok(())
+        }
+    }
+}
+
+/// Reply the status of connecting to `host`:`port` after checking the port against the
+/// allowlist.
+async fn connect(stream: &mut TcpStream, host: &str, port: u16) -> std::io::Result<TcpStream> {
+    if !CONFIG.access.port_allowed(port) {
+        stream.write_u8(STATUS_NOT_ALLOWED).await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("connection to {}:{} is not allowed", host, port),
+        ));
+    }
+
+    match TcpStream::connect((host, port)).await {
+        Ok(server) => {
+            stream.write_u8(STATUS_SUCCESS).await?;
+            Ok(server)
+        }
+        Err(err) => {
+            stream.write_u8(error_status(&err)).await?;
+            Err(err)
+        }
+    }
+}
+
+/// Handle `tcp\0` requests, which predate status replies: a denied or failed connection is
+/// only signalled by closing the stream.
+pub async fn handle_tcp(mut stream: TcpStream) -> std::io::Result<()> {
+    let port = stream.read_u16().await?;
+    if !CONFIG.access.port_allowed(port) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("connection to 127.0.0.1:{} is not allowed", port),
+        ));
+    }
+    let server = TcpStream::connect(("127.0.0.1", port)).await?;
+    forward(stream, server).await
+}
+
+/// Handle `tcps` requests, which are `tcp\0` requests with a status reply.
+pub async fn handle_tcp_status(mut stream: TcpStream) -> std::io::Result<()> {
+    let port = stream.read_u16().await?;
+    let server = connect(&mut stream, "127.0.0.1", port).await?;
+    forward(stream, server).await
+}
+
+async fn read_addr(stream: &mut TcpStream) -> std::io::Result<SocketAddr> {
+    let ip = match stream.read_u8().await? {
+        4 => IpAddr::V4(Ipv4Addr::from(stream.read_u32().await?)),
+        6 => IpAddr::V6(Ipv6Addr::from(stream.read_u128().await?)),
+        family => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown address family {}", family),
+            ))
+        }
+    };
+    let port = stream.read_u16().await?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+// Like `tcp\0`, but with a PROXY protocol header carrying the original addresses in WSL.
+pub async fn handle_tcp_proxy(mut stream: TcpStream) -> std::io::Result<()> {
+    let port = stream.read_u16().await?;
+    let version = stream.read_u8().await?;
+    let source = read_addr(&mut stream).await?;
+    let destination = read_addr(&mut stream).await?;
+    let header = proxy_protocol::header(version, source, destination)?;
+
+    let mut server = connect(&mut stream, "127.0.0.1", port).await?;
+    server.write_all(&header).await?;
+    forward(stream, server).await
+}
+
+pub async fn handle_tcp_host(mut stream: TcpStream) -> std::io::Result<()> {
+    let len = stream.read_u8().await?;
+    let mut host = vec![0; len as usize];
+    stream.read_exact(&mut host).await?;
+    let host = String::from_utf8(host).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    let port = stream.read_u16().await?;
+
+    if !CONFIG.access.host_allowed(&host) {
+        stream.write_u8(STATUS_NOT_ALLOWED).await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("host {} is not allowed", host),
+        ));
+    }
+
+    // Host names are resolved on Windows, so they follow Windows DNS settings.
+    let server = connect(&mut stream, &host, port).await?;
+    forward(stream, server).await
+}
+
+pub async fn handle_tcp_listeners(mut stream: TcpStream) -> std::io::Result<()> {
+    let listeners = netstat::tcp_loopback_listeners()?;
+    write_listeners(&mut stream, listeners, |port| {
+        CONFIG.access.port_allowed(port)
+    })
+    .await
+}
+
+// Reply with the count and the list of listening ports. Only ports that can be connected to are
+// reported.
+async fn write_listeners<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    listeners: BTreeSet<u16>,
+    allowed: impl Fn(u16) -> bool,
+) -> std::io::Result<()> {
+    let ports: Vec<u16> = listeners
+        .into_iter()
+        .filter(|&port| allowed(port))
+        .collect();
+
+    let mut buf = Vec::with_capacity(2 + ports.len() * 2);
+    buf.extend_from_slice(&(ports.len() as u16).to_be_bytes());
+    for port in ports {
+        buf.extend_from_slice(&port.to_be_bytes());
+    }
+    w.write_all(&buf).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stands in for the listening-port table of Windows.
+    fn fixed_table() -> BTreeSet<u16> {
+        [22, 80, 3000, 8080].into_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn listeners_reply() {
+        let mut buf = Vec::new();
+        write_listeners(&mut buf, fixed_table(), |_| true)
+            .await
+            .unwrap();
+        assert_eq!(buf, [0, 4, 0, 22, 0, 80, 0x0b, 0xb8, 0x1f, 0x90]);
+    }
+
+    #[tokio::test]
+    async fn listeners_reply_filters_denied_ports() {
+        let mut buf = Vec::new();
+        write_listeners(&mut buf, fixed_table(), |port| port >= 1024)
+            .await
+            .unwrap();
+        assert_eq!(buf, [0, 2, 0x0b, 0xb8, 0x1f, 0x90]);
+
+        let mut buf = Vec::new();
+        write_listeners(&mut buf, fixed_table(), |_| false)
+            .await
+            .unwrap();
+        assert_eq!(buf, [0, 0]);
+    }
+
+    #[tokio::test]
+    async fn half_closed_connection_finishes_response() {
+        let activity = Activity::new();
+        let responded = std::sync::atomic::AtomicBool::new(false);
+        let request = async { Ok(()) };
+        let response = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            responded.store(true, Ordering::Relaxed);
+            Ok(())
+        };
+        half_close(request, response, &activity).await.unwrap();
+        assert!(responded.load(Ordering::Relaxed));
+    }
 }
@@ -0,0 +1,44 @@
+use super::tcp::STATUS_NOT_ALLOWED;
+use super::util::{either, read_frame, write_frame};
+use super::CONFIG;
+
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+pub async fn handle_udp(mut stream: TcpStream) -> std::io::Result<()> {
+    let port = stream.read_u16().await?;
+    if !CONFIG.access.port_allowed(port) {
+        stream.write_u8(STATUS_NOT_ALLOWED).await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("UDP port {} is not allowed", port),
+        ));
+    }
+
+    let server = UdpSocket::bind(("127.0.0.1", 0)).await?;
+    server.connect(("127.0.0.1", port)).await?;
+    stream.write_u8(0).await?;
+
+    let (mut client_r, mut client_w) = stream.split();
+
+    let a = async {
+        while let Some(datagram) = read_frame(&mut client_r).await? {
+            server.send(&datagram).await?;
+        }
+        Ok(())
+    };
+    let b = async {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let size = match server.recv(&mut buf).await {
+                Ok(size) => size,
+                // Windows reports ICMP port unreachable of previous sends this way.
+                Err(err) if err.kind() == ErrorKind::ConnectionReset => continue,
+                Err(err) => return Err(err),
+            };
+            write_frame(&mut client_w, &buf[..size]).await?;
+        }
+    };
+    either(a, b).await
+}
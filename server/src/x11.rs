@@ -1,15 +1,84 @@
 use super::util::{connect_stream, either};
 use super::CONFIG;
 
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
-pub async fn handle_x11(mut stream: TcpStream) -> std::io::Result<()> {
-    let (client_r, client_w) = stream.split();
+pub async fn handle_x11(stream: TcpStream) -> std::io::Result<()> {
+    forward(stream, 0).await
+}
+
+// Like `x11\0`, but with the index of the X server to connect to.
+pub async fn handle_x11_display(mut stream: TcpStream) -> std::io::Result<()> {
+    let index = stream.read_u8().await?;
+    forward(stream, index).await
+}
 
-    let mut server = TcpStream::connect(&CONFIG.x11.display).await?;
+async fn forward(mut stream: TcpStream, index: u8) -> std::io::Result<()> {
+    let display = CONFIG.x11.display(index).ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("X server {} is not configured", index),
+        )
+    })?;
+
+    let cookie = CONFIG.x11.cookie(index)?;
+
+    let mut server = TcpStream::connect(display).await?;
     server.set_nodelay(true)?;
+
+    // The guest stripped the authorization of the client, put in the real cookie.
+    if let Some(cookie) = cookie {
+        let setup = read_setup(&mut stream, &cookie).await?;
+        server.write_all(&setup).await?;
+    }
+
+    let (client_r, client_w) = stream.split();
     let (server_r, server_w) = server.split();
-    let a = connect_stream(client_r, server_w);
-    let b = connect_stream(server_r, client_w);
+    let a = connect_stream(client_r, server_w, |_| ());
+    let b = connect_stream(server_r, client_w, |_| ());
     either(a, b).await
 }
+
+const MIT_MAGIC_COOKIE: &[u8] = b"MIT-MAGIC-COOKIE-1";
+
+fn pad(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+// Read the connection setup request of an X11 client, and encode it again with `cookie` as the
+// authorization.
+async fn read_setup<R: AsyncRead + Unpin>(r: &mut R, cookie: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut header = [0; 12];
+    r.read_exact(&mut header).await?;
+    let big_endian = match header[0] {
+        b'B' => true,
+        b'l' => false,
+        _ => return Err(Error::new(ErrorKind::InvalidData, "invalid X11 byte order")),
+    };
+    let from = |bytes: [u8; 2]| match big_endian {
+        true => u16::from_be_bytes(bytes),
+        false => u16::from_le_bytes(bytes),
+    };
+    let to = |value: u16| match big_endian {
+        true => value.to_be_bytes(),
+        false => value.to_le_bytes(),
+    };
+
+    // Skip the authorization sent by the client.
+    let name_len = from([header[6], header[7]]) as usize;
+    let data_len = from([header[8], header[9]]) as usize;
+    let mut auth = vec![0; name_len + pad(name_len) + data_len + pad(data_len)];
+    r.read_exact(&mut auth).await?;
+
+    let mut setup = header[..6].to_vec();
+    setup.extend_from_slice(&to(MIT_MAGIC_COOKIE.len() as u16));
+    setup.extend_from_slice(&to(cookie.len() as u16));
+    setup.extend_from_slice(&[0, 0]);
+    setup.extend_from_slice(MIT_MAGIC_COOKIE);
+    setup.resize(setup.len() + pad(MIT_MAGIC_COOKIE.len()), 0);
+    setup.extend_from_slice(cookie);
+    setup.resize(setup.len() + pad(cookie.len()), 0);
+    Ok(setup)
+}